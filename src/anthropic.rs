@@ -0,0 +1,95 @@
+use serde::Serialize;
+
+use crate::error::AskError;
+use crate::{Answer, Message, OpenAIRequest};
+
+pub const DEFAULT_API_BASE: &str = "https://api.anthropic.com/v1/messages";
+pub const DEFAULT_MODEL: &str = "claude-sonnet-4-5";
+pub const API_VERSION: &str = "2023-06-01";
+
+// the messages API requires max_tokens, so use this when --max-tokens isn't given
+const DEFAULT_MAX_TOKENS: i64 = 4096;
+
+#[derive(Debug, Serialize)]
+pub struct AnthropicRequest<'a> {
+    model: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    system: Option<String>,
+    messages: Vec<&'a Message>,
+    max_tokens: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop_sequences: Option<&'a Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<Metadata<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Metadata<'a> {
+    user_id: &'a str,
+}
+
+impl<'a> From<&'a OpenAIRequest> for AnthropicRequest<'a> {
+    fn from(request: &'a OpenAIRequest) -> Self {
+        // system messages are a top-level field rather than part of the conversation
+        let system: Vec<String> = request
+            .messages
+            .iter()
+            .filter(|message| message.role == "system")
+            .map(|message| message.content.text())
+            .collect();
+
+        AnthropicRequest {
+            model: &request.model,
+            system: (!system.is_empty()).then(|| system.join("\n\n")),
+            messages: request
+                .messages
+                .iter()
+                .filter(|message| message.role != "system")
+                .collect(),
+            max_tokens: request.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS),
+            temperature: request.temperature,
+            top_p: request.top_p,
+            stop_sequences: request.stop.as_ref(),
+            stream: request.stream,
+            metadata: request.user.as_deref().map(|user_id| Metadata { user_id }),
+        }
+    }
+}
+
+// the answer of a messages API response, explaining why when there is no text
+pub fn parse_answer(response: &serde_json::Value) -> Result<Answer, AskError> {
+    let stop_reason = response["stop_reason"].as_str().map(str::to_string);
+    let text = response["content"]
+        .as_array()
+        .and_then(|blocks| blocks.iter().find(|block| block["type"] == "text"))
+        .and_then(|block| block["text"].as_str());
+
+    let content = match (text, stop_reason.as_deref()) {
+        (Some(text), _) => text.to_string(),
+        (None, Some("refusal")) => return Err(AskError::Api("the model refused to answer".to_string())),
+        (None, Some(reason)) => {
+            return Err(AskError::Response(format!("the answer has no text (stop_reason: {})", reason)))
+        }
+        (None, None) => return Err(AskError::Response("missing content[0].text".to_string())),
+    };
+
+    Ok(Answer {
+        content,
+        prompt_tokens: response["usage"]["input_tokens"].as_i64(),
+        completion_tokens: response["usage"]["output_tokens"].as_i64(),
+        reasoning_tokens: None,
+        system_fingerprint: None,
+        service_tier: None,
+        finish_reason: stop_reason,
+        other_choices: vec![],
+        logprobs: vec![],
+        first_token_at: None,
+        tool_calls: vec![],
+    })
+}
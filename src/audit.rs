@@ -0,0 +1,34 @@
+use chrono::Utc;
+use fs2::FileExt;
+use sha2::{Digest, Sha256};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+use crate::error::AskError;
+use crate::Completion;
+
+// append one JSON line per answered request to the audit log. the prompt is only kept as a hash,
+// and the file is locked so concurrent runs don't interleave their lines
+pub fn record(path: &Path, model: &str, prompt: &str, completion: &Completion) -> Result<(), AskError> {
+    let entry = serde_json::json!({
+        "timestamp": Utc::now().to_rfc3339(),
+        "model": model,
+        "prompt_sha256": format!("{:x}", Sha256::digest(prompt)),
+        "prompt_tokens": completion.prompt_tokens,
+        "completion_tokens": completion.completion_tokens,
+        "latency_ms": completion.latency.map(|latency| latency.as_millis() as u64),
+        "finish_reason": completion.finish_reason,
+        "cached": completion.cached,
+    });
+
+    if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    file.lock_exclusive()?;
+    let result = writeln!(file, "{}", entry);
+    file.unlock()?;
+    result?;
+    Ok(())
+}
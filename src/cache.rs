@@ -0,0 +1,145 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{fs, io, path::Path, path::PathBuf};
+
+use crate::error::AskError;
+use crate::Completion;
+
+// how long a cached answer is used when cache_ttl_hours isn't set
+const DEFAULT_TTL_HOURS: i64 = 24 * 7;
+
+#[derive(Serialize, Deserialize)]
+struct CacheEntry {
+    created_at: String,
+    completion: Completion,
+}
+
+// answers to identical requests, one file per request in ~/.ask/cache
+pub struct ResponseCache {
+    dir: PathBuf,
+    ttl: Duration,
+    // questions about files are answered the same whatever was asked before them
+    ignore_history: bool,
+}
+
+impl ResponseCache {
+    pub fn new(ask_dir: &Path, ttl_hours: Option<i64>, ignore_history: bool) -> Self {
+        ResponseCache {
+            dir: cache_dir(ask_dir),
+            ttl: Duration::hours(ttl_hours.unwrap_or(DEFAULT_TTL_HOURS)),
+            ignore_history,
+        }
+    }
+
+    pub fn key(&self, api_base: &str, request: &serde_json::Value) -> String {
+        key(api_base, request, self.ignore_history)
+    }
+
+    // a missing, unreadable or expired entry is a miss
+    pub fn get(&self, key: &str) -> Option<Completion> {
+        let text = fs::read_to_string(self.dir.join(format!("{}.json", key))).ok()?;
+        let entry: CacheEntry = serde_json::from_str(&text).ok()?;
+        let created_at = DateTime::parse_from_rfc3339(&entry.created_at).ok()?;
+        let fresh = Utc::now().signed_duration_since(created_at) < self.ttl;
+        fresh.then_some(entry.completion)
+    }
+
+    pub fn put(&self, key: &str, completion: &Completion) -> Result<(), AskError> {
+        let entry = serde_json::json!({
+            "created_at": Utc::now().to_rfc3339(),
+            "completion": completion,
+        });
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.dir.join(format!("{}.json", key)), serde_json::to_string(&entry)?)?;
+        Ok(())
+    }
+}
+
+fn cache_dir(ask_dir: &Path) -> PathBuf {
+    ask_dir.join("cache")
+}
+
+// the endpoint and the request body, leaving out whether the answer is streamed. without the history only the
+// system messages and the prompt are kept, the prompt has the contents of the files it asks about
+fn key(api_base: &str, request: &serde_json::Value, ignore_history: bool) -> String {
+    let mut request = request.clone();
+    if let Some(fields) = request.as_object_mut() {
+        fields.remove("stream");
+        fields.remove("stream_options");
+        if let (true, Some(messages)) = (ignore_history, fields.get_mut("messages").and_then(|x| x.as_array_mut())) {
+            let prompt = messages.pop();
+            messages.retain(|message| message["role"] == "system");
+            messages.extend(prompt);
+        }
+    }
+    let digest = Sha256::new()
+        .chain_update(api_base)
+        .chain_update([0])
+        .chain_update(request.to_string())
+        .finalize();
+    format!("{:x}", digest)
+}
+
+// remove every cached answer, returning how many there were
+pub fn clear(ask_dir: &Path) -> Result<usize, AskError> {
+    let entries = match fs::read_dir(cache_dir(ask_dir)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e.into()),
+    };
+    let mut removed = 0;
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "json") {
+            fs::remove_file(path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(messages: serde_json::Value) -> serde_json::Value {
+        serde_json::json!({ "model": "gpt-4o", "temperature": 0.7, "messages": messages })
+    }
+
+    #[test]
+    fn key_ignores_history_for_questions_about_files() {
+        let first = request(serde_json::json!([
+            { "role": "system", "content": "Be brief." },
+            { "role": "user", "content": "review f.rs:\n```rs\nfn main() {}\n```" },
+        ]));
+        let later = request(serde_json::json!([
+            { "role": "system", "content": "Be brief." },
+            { "role": "user", "content": "hi" },
+            { "role": "assistant", "content": "hello" },
+            { "role": "user", "content": "review f.rs:\n```rs\nfn main() {}\n```" },
+        ]));
+        assert_eq!(key("https://api", &first, true), key("https://api", &later, true));
+        assert_ne!(key("https://api", &first, false), key("https://api", &later, false));
+    }
+
+    #[test]
+    fn key_changes_with_the_file_contents_and_settings() {
+        let prompt = |code: &str| serde_json::json!([{ "role": "user", "content": format!("review f.rs:\n{}", code) }]);
+        let base = key("https://api", &request(prompt("fn main() {}")), true);
+        assert_ne!(base, key("https://api", &request(prompt("fn main() { todo!() }")), true));
+        let mut other_model = request(prompt("fn main() {}"));
+        other_model["model"] = serde_json::json!("gpt-4o-mini");
+        assert_ne!(base, key("https://api", &other_model, true));
+        assert_ne!(base, key("https://other", &request(prompt("fn main() {}")), true));
+    }
+
+    #[test]
+    fn key_ignores_streaming() {
+        let mut streamed = request(serde_json::json!([{ "role": "user", "content": "hi" }]));
+        let plain = streamed.clone();
+        streamed["stream"] = serde_json::json!(true);
+        streamed["stream_options"] = serde_json::json!({ "include_usage": true });
+        assert_eq!(key("https://api", &plain, false), key("https://api", &streamed, false));
+    }
+}
@@ -0,0 +1,135 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::{fs, io, path::{Path, PathBuf}};
+
+use crate::error::AskError;
+use crate::tools::ToolConfig;
+
+// written on first run so the available settings are easy to discover
+const DEFAULT_CONFIG: &str = r#"# Settings for ask. Command line flags and environment variables take precedence.
+
+# The model to use when neither --model nor CHATGPT_CLI_MODEL is set
+# model = "gpt-3.5-turbo"
+
+# The endpoint for OpenAI requests when OPENAI_API_BASE is not set
+# api_base = "https://api.openai.com/v1/chat/completions"
+
+# Seconds to wait for an answer when CHATGPT_CLI_REQUEST_TIMEOUT_SECS is not set
+timeout_secs = 120
+
+# The maximum number of tokens to generate for an answer
+# max_tokens = 1000
+
+# How many tokens of conversation history to send when ASK_CONTEXT_TOKENS is not set
+# context_tokens = 4000
+
+# Sampling temperature between 0.0 and 2.0
+# temperature = 1.0
+
+# Repetition penalties between -2.0 and 2.0, providers that don't support them ignore them
+# presence_penalty = 0.0
+# frequency_penalty = 0.0
+
+# Drop older history on every run, beyond this many entries or this many days (ask --prune runs it by hand)
+# max_log_entries = 500
+# max_log_age_days = 90
+
+# Write new conversation histories as "yaml" for editing them by hand, the default is "json".
+# Existing files are read by their extension either way
+# log_format = "json"
+
+# How long answers cached with --cache or ASK_CACHE=1 are used, and whether to cache
+# answers at any temperature rather than only deterministic ones (temperature 0)
+# cache_ttl_hours = 168
+# cache_any_temperature = false
+
+# The system prompt when neither --system nor ASK_SYSTEM_PROMPT is set
+# system_prompt = "You are a helpful assistant."
+
+# The message next to the spinner while waiting, {model} is replaced with the model name
+# spinner_message = "Thinking… (model: {model})"
+
+# Sent as the end user with every request, for per-user attribution on a shared key (--user-id overrides it)
+# user_id = "alice"
+
+# Short names for models, e.g. ask -m fast "..."
+# [aliases]
+# big = "gpt-4o"
+# fast = "gpt-4o-mini"
+
+# Rename message roles for servers that reject some of them, e.g. ones without system messages.
+# role_prefix goes before the text of renamed messages, {role} is replaced with the original role
+# role_prefix = "[{role}] "
+# [roles]
+# system = "user"
+
+# Commands the model may run with --tools, besides the built-in get_current_time. The arguments
+# arrive as JSON on stdin and in ASK_TOOL_ARGS, whatever the command prints is the result
+# [tools.weather]
+# description = "The current weather in a city"
+# command = "curl -s \"wttr.in/$(jq -r .city)?format=3\""
+# parameters = { type = "object", properties = { city = { type = "string" } }, required = ["city"] }
+"#;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub model: Option<String>,
+    pub api_base: Option<String>,
+    pub timeout_secs: Option<u64>,
+    pub max_tokens: Option<i64>,
+    pub context_tokens: Option<i64>,
+    pub temperature: Option<f32>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub system_prompt: Option<String>,
+    pub max_log_entries: Option<usize>,
+    pub max_log_age_days: Option<i64>,
+    pub log_format: Option<LogFormat>,
+    pub cache_ttl_hours: Option<i64>,
+    pub cache_any_temperature: Option<bool>,
+    pub spinner_message: Option<String>,
+    pub user_id: Option<String>,
+    pub aliases: HashMap<String, String>,
+    pub roles: HashMap<String, String>,
+    pub role_prefix: Option<String>,
+    pub tools: HashMap<String, ToolConfig>,
+}
+
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Json,
+    Yaml,
+}
+
+impl LogFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            LogFormat::Json => "json",
+            LogFormat::Yaml => "yaml",
+        }
+    }
+}
+
+pub fn path(ask_dir: &Path) -> PathBuf {
+    ask_dir.join("config.toml")
+}
+
+// load ~/.ask/config.toml, creating it with the defaults if it doesn't exist yet
+pub fn load(ask_dir: &Path) -> Result<Config, AskError> {
+    let path = path(ask_dir);
+    let text = match fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            fs::create_dir_all(ask_dir)?;
+            fs::write(&path, DEFAULT_CONFIG)?;
+            DEFAULT_CONFIG.to_string()
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    toml::from_str(&text)
+        .map_err(|e| AskError::Config(format!("invalid config file {}: {}", path.display(), e)))
+}
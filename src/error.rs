@@ -0,0 +1,89 @@
+use std::{fmt, io};
+
+pub enum AskError {
+    Config(String),
+    Network(reqwest::Error),
+    Io(io::Error),
+    Json(serde_json::Error),
+    Yaml(serde_yaml::Error),
+    Response(String),
+    Api(String),
+    // the key is missing or the provider rejected it
+    Auth(String),
+}
+
+impl AskError {
+    // stable exit codes for scripts: 1 generic, 2 usage, 3 network, 4 API, 5 auth
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            AskError::Config(_) => 2,
+            AskError::Network(_) => 3,
+            AskError::Api(_) => 4,
+            AskError::Auth(_) => 5,
+            AskError::Io(_) | AskError::Json(_) | AskError::Yaml(_) | AskError::Response(_) => 1,
+        }
+    }
+}
+
+impl fmt::Display for AskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AskError::Config(message) => write!(f, "{}", message),
+            AskError::Network(e) => write!(f, "could not reach the API: {}", e),
+            AskError::Io(e) => write!(f, "{}", e),
+            AskError::Json(e) => write!(f, "invalid JSON: {}", e),
+            AskError::Yaml(e) => write!(f, "invalid YAML: {}", e),
+            AskError::Response(message) => write!(f, "unexpected response from the API: {}", message),
+            AskError::Api(message) => write!(f, "the API returned an error: {}", message),
+            AskError::Auth(message) => write!(f, "authentication failed: {}", message),
+        }
+    }
+}
+
+// keep the Debug representation as readable as Display, e.g. for unwrap()
+impl fmt::Debug for AskError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for AskError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AskError::Network(e) => Some(e),
+            AskError::Io(e) => Some(e),
+            AskError::Json(e) => Some(e),
+            AskError::Yaml(e) => Some(e),
+            AskError::Config(_) | AskError::Response(_) | AskError::Api(_) | AskError::Auth(_) => None,
+        }
+    }
+}
+
+impl From<reqwest::Error> for AskError {
+    fn from(e: reqwest::Error) -> Self {
+        // a body that can't be decoded means the API answered with something unexpected
+        if e.is_decode() {
+            AskError::Response(e.to_string())
+        } else {
+            AskError::Network(e)
+        }
+    }
+}
+
+impl From<io::Error> for AskError {
+    fn from(e: io::Error) -> Self {
+        AskError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for AskError {
+    fn from(e: serde_json::Error) -> Self {
+        AskError::Json(e)
+    }
+}
+
+impl From<serde_yaml::Error> for AskError {
+    fn from(e: serde_yaml::Error) -> Self {
+        AskError::Yaml(e)
+    }
+}
@@ -0,0 +1,101 @@
+use chrono::{DateTime, Duration, Local, Utc};
+use owo_colors::OwoColorize;
+
+use crate::Log;
+
+// e.g. 2026-10-15 09:17, falling back to the stored text if it isn't RFC 3339
+fn format_timestamp(timestamp: &str) -> String {
+    DateTime::parse_from_rfc3339(timestamp)
+        .map(|time| time.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string())
+        .unwrap_or_else(|_| timestamp.to_string())
+}
+
+fn role_label(role: &str) -> &str {
+    match role {
+        "user" => "You",
+        "assistant" => "Assistant",
+        "system" => "System",
+        "summary" => "Summary",
+        "tool" => "Tool",
+        role => role,
+    }
+}
+
+// e.g. "5 minutes ago", older entries get the date
+fn relative_time(timestamp: &str) -> String {
+    let Ok(time) = DateTime::parse_from_rfc3339(timestamp) else {
+        return timestamp.to_string();
+    };
+    let age = Utc::now().signed_duration_since(time);
+    let plural = |n: i64, unit: &str| format!("{} {}{} ago", n, unit, if n == 1 { "" } else { "s" });
+    match age {
+        age if age < Duration::minutes(1) => "just now".to_string(),
+        age if age < Duration::hours(1) => plural(age.num_minutes(), "minute"),
+        age if age < Duration::days(1) => plural(age.num_hours(), "hour"),
+        age if age < Duration::days(7) => plural(age.num_days(), "day"),
+        _ => format_timestamp(timestamp),
+    }
+}
+
+// the last entries for reading in the terminal, with the roles in color
+pub fn format_recent(chatlog: &[Log], count: Option<usize>, color: bool) -> String {
+    let start = count.map_or(0, |count| chatlog.len().saturating_sub(count));
+    let mut output = String::new();
+    for log in &chatlog[start..] {
+        let label = role_label(&log.role);
+        let label = match (color, log.role.as_str()) {
+            (false, _) => label.to_string(),
+            (true, "user") => label.cyan().bold().to_string(),
+            (true, "assistant") => label.green().bold().to_string(),
+            (true, _) => label.yellow().bold().to_string(),
+        };
+        let time = format!("({})", relative_time(&log.timestamp));
+        let time = if color { time.dimmed().to_string() } else { time };
+        output.push_str(&format!("{} {}\n{}\n\n", label, time, log.content.trim_end()));
+    }
+    output
+}
+
+// a readable transcript, the content is kept as is so code blocks survive
+pub fn to_markdown(chatlog: &[Log]) -> String {
+    let mut markdown = String::new();
+    for log in chatlog {
+        markdown.push_str(&format!(
+            "**{}:** _{}_\n\n{}\n\n",
+            role_label(&log.role),
+            format_timestamp(&log.timestamp),
+            log.content.trim_end()
+        ));
+    }
+    markdown
+}
+
+// drop entries older than max_age_days and all but the newest max_entries, keeping the system prompt in use.
+// returns how many were dropped
+pub fn prune(chatlog: &mut Vec<Log>, max_entries: Option<usize>, max_age_days: Option<i64>) -> usize {
+    let before = chatlog.len();
+    let system_index = chatlog.iter().rposition(|log| log.role == "system");
+    let keep_from = max_entries.map_or(0, |max| before.saturating_sub(max));
+    let cutoff = max_age_days.map(|days| Utc::now() - Duration::days(days));
+
+    let mut index = 0;
+    chatlog.retain(|log| {
+        let position = index;
+        index += 1;
+        let too_old = cutoff.is_some_and(|cutoff| {
+            DateTime::parse_from_rfc3339(&log.timestamp).is_ok_and(|time| time < cutoff)
+        });
+        Some(position) == system_index || (position >= keep_from && !too_old)
+    });
+
+    // the history shouldn't start with the answer to a question that was dropped
+    if chatlog.len() < before {
+        while let Some(first) = chatlog.iter().position(|log| log.role != "system") {
+            if chatlog[first].role != "assistant" {
+                break;
+            }
+            chatlog.remove(first);
+        }
+    }
+    before - chatlog.len()
+}
@@ -0,0 +1,70 @@
+use rustyline::config::Config;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+
+use crate::error::AskError;
+
+// how many prompts ~/.ask/repl_history keeps
+const MAX_HISTORY: usize = 1000;
+
+// reads the REPL prompts, with line editing and the prompts of earlier sessions on the arrow keys
+// when stdin is a terminal, plain lines otherwise (e.g. piped input or a terminal rustyline can't drive)
+pub enum LineReader {
+    Editor { editor: Box<DefaultEditor>, history_path: PathBuf },
+    Plain,
+}
+
+impl LineReader {
+    pub fn new(history_path: PathBuf) -> Self {
+        if !io::stdin().is_terminal() {
+            return LineReader::Plain;
+        }
+        let config = match Config::builder().max_history_size(MAX_HISTORY) {
+            Ok(builder) => builder.auto_add_history(false).build(),
+            Err(_) => return LineReader::Plain,
+        };
+        match DefaultEditor::with_config(config) {
+            Ok(mut editor) => {
+                // there is no history file before the first session
+                editor.load_history(&history_path).ok();
+                LineReader::Editor { editor: Box::new(editor), history_path }
+            }
+            Err(_) => LineReader::Plain,
+        }
+    }
+
+    // the next line, None at the end of the input. Ctrl-C clears the line being typed
+    pub fn read_line(&mut self, prompt: &str) -> Result<Option<String>, AskError> {
+        match self {
+            LineReader::Editor { editor, history_path } => loop {
+                match editor.readline(prompt) {
+                    Ok(line) => {
+                        if !line.trim().is_empty() {
+                            editor.add_history_entry(line.as_str()).ok();
+                            if let Err(e) = editor.save_history(history_path) {
+                                eprintln!("Warning: could not save the prompt history: {}", e);
+                            }
+                        }
+                        return Ok(Some(line));
+                    }
+                    Err(ReadlineError::Interrupted) => continue,
+                    Err(ReadlineError::Eof) => return Ok(None),
+                    Err(ReadlineError::Io(e)) => return Err(e.into()),
+                    Err(e) => return Err(io::Error::other(e.to_string()).into()),
+                }
+            },
+            LineReader::Plain => {
+                print!("{}", prompt);
+                io::stdout().flush()?;
+                let mut line = String::new();
+                if io::stdin().lock().read_line(&mut line)? == 0 {
+                    println!();
+                    return Ok(None);
+                }
+                Ok(Some(line))
+            }
+        }
+    }
+}
@@ -1,49 +1,309 @@
-use clap::Parser;
-use dirs;
-use reqwest::blocking::Client;
-use reqwest::header::{HeaderMap, AUTHORIZATION, CONTENT_TYPE};
+mod anthropic;
+mod audit;
+mod cache;
+mod config;
+mod error;
+mod history;
+mod input;
+mod models;
+mod persona;
+mod prompt;
+mod ratelimit;
+mod render;
+mod session;
+mod stats;
+mod template;
+mod tools;
+
+use anthropic::AnthropicRequest;
+use cache::ResponseCache;
+use config::LogFormat;
+use ratelimit::RateLimit;
+use arboard::Clipboard;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use error::AskError;
+use reqwest::{Client, NoProxy, Proxy, RequestBuilder, Response};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, AUTHORIZATION, CONTENT_TYPE, RETRY_AFTER};
+use reqwest::StatusCode;
 use serde::{Deserialize, Serialize};
-use std::fs::OpenOptions;
-use std::time::Duration;
+use fs2::FileExt;
+use std::fs::{File, OpenOptions};
+use std::collections::HashMap;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Once;
+use tokio::signal;
+use tokio::sync::Notify;
+use std::time::{Duration, Instant};
 use std::{
     env,
-    path::PathBuf,
+    ffi::OsString,
+    path::{Path, PathBuf},
     env::current_exe,
     fs::{self},
-    io::{Error, Read},
+    io::{self, IsTerminal, Read, Write},
 };
 use chrono::Utc;
+use terminal_size::{terminal_size, Width};
+use url::{Host, Url};
 use indicatif::{ProgressBar, ProgressStyle};
+use tiktoken_rs::{cl100k_base_singleton, o200k_base_singleton};
 
-const MAX_TOKENS: i64 = 2000;
+// history budget for models whose context window we don't know
+const DEFAULT_CONTEXT_TOKENS: i64 = 2000;
 const DEFAULT_TIMEOUT_SECS: u64 = 120;
+const DEFAULT_MAX_RETRIES: u32 = 3;
+// how often a reset or refused connection is tried again
+const MAX_TRANSPORT_RETRIES: u32 = 2;
+// real keys are much longer, a shorter one was most likely cut off when it was copied
+const MIN_API_KEY_LEN: usize = 20;
+// the instructions for condensing old turns with --summarize-history
+const SUMMARY_PROMPT: &str = "Summarize the following conversation in a few short paragraphs. \
+Keep the facts, decisions, code and open questions needed to continue it.";
+// the instructions for naming a session after its first question
+const TITLE_PROMPT: &str = "Write a title of at most six words for a conversation that starts with the \
+following message. Answer with the title only.";
+const CONTINUE_PROMPT: &str = "Continue exactly where your last answer stopped, without repeating any of it.";
+// shown next to the spinner while waiting, {model} is replaced with the model name
+const DEFAULT_SPINNER_MESSAGE: &str = "Thinking… (model: {model})";
+// the most stop sequences OpenAI accepts
+const MAX_STOP_SEQUENCES: usize = 4;
+// the chat format adds a few tokens around every message
+const TOKENS_PER_MESSAGE: i64 = 4;
+const OLLAMA_API_BASE: &str = "http://localhost:11434/v1/chat/completions";
+// used when AZURE_OPENAI_API_VERSION isn't set
+const AZURE_API_VERSION: &str = "2024-10-21";
+// warn when a request takes more than this share of the model's context window
+const CONTEXT_WARNING_RATIO: f64 = 0.8;
+// how many times in a row the model may call tools before it has to answer
+const MAX_TOOL_ROUNDS: usize = 8;
+
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Print a completion script for the shell, e.g. ask completions zsh > ~/.zfunc/_ask
+    #[clap(hide = true)]
+    Completions { shell: Shell },
+    /// Ask a question, the same as leaving out the subcommand, e.g. ask chat -m gpt-4o "..."
+    Chat {
+        /// The prompt, here it may start with the name of a subcommand
+        prompt: Vec<String>,
+    },
+    /// Show the conversation so far
+    History {
+        /// Only the last N entries
+        n: Option<usize>,
+    },
+    /// Clear the conversation history
+    Clear,
+    /// List the saved sessions
+    Sessions,
+    /// Export the conversation as a Markdown transcript
+    Export {
+        /// The file to write, - for stdout
+        path: String,
+    },
+    /// List the models available from the provider
+    Models {
+        /// Only list models that work with chat completions
+        #[clap(long)]
+        chat_only: bool,
+    },
+    /// Print the config file, creating it with the defaults if needed
+    Config {
+        /// Only print where the config file is
+        #[clap(long)]
+        path: bool,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Provider {
+    Openai,
+    Anthropic,
+    Azure,
+}
+
+// what the model must answer with, json is checked before the answer is used
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum AnswerFormat {
+    Text,
+    Json,
+}
 
+// how OpenAI processes the request: flex is cheaper but slower, priority faster but dearer
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ServiceTier {
+    Auto,
+    Default,
+    Flex,
+    Priority,
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 struct Log {
     timestamp: String,
     role: String,
     content: String,
+    // missing in chatlogs from before token tracking, recounted on load
+    #[serde(default)]
     tokens: i64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Clone, Serialize, Deserialize, Debug)]
 struct Message {
     role: String,
-    content: String,
+    content: MessageContent,
+    // the tools an assistant message asked to call, and the call a tool message answers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_calls: Option<Vec<serde_json::Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_call_id: Option<String>,
+}
+
+// plain text, or text with images in the array-of-parts format. text stays a bare string on the wire
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+enum MessageContent {
+    Text(String),
+    Parts(Vec<ContentPart>),
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ContentPart {
+    Text { text: String },
+    ImageUrl { image_url: ImageUrl },
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+struct ImageUrl {
+    url: String,
+}
+
+impl MessageContent {
+    // the text of the message, leaving out any images
+    fn text(&self) -> String {
+        match self {
+            MessageContent::Text(text) => text.clone(),
+            MessageContent::Parts(parts) => parts
+                .iter()
+                .filter_map(|part| match part {
+                    ContentPart::Text { text } => Some(text.as_str()),
+                    ContentPart::ImageUrl { .. } => None,
+                })
+                .collect::<Vec<_>>()
+                .join("\n"),
+        }
+    }
 }
+
 #[derive(Debug, Deserialize, Serialize)]
 struct OpenAIRequest {
     #[serde(rename = "model")]
     model: String,
     #[serde(rename = "messages")]
     messages: Vec<Message>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    presence_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    frequency_penalty: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_tokens: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_completion_tokens: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seed: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    n: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    logprobs: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    top_logprobs: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stop: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    response_format: Option<ResponseFormat>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    service_tier: Option<ServiceTier>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tools: Option<Vec<serde_json::Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stream_options: Option<StreamOptions>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct ResponseFormat {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct StreamOptions {
+    include_usage: bool,
+}
+
+// the answer as the provider sent it, streamed or not. some servers leave out the usage
+#[derive(Default)]
+struct Answer {
+    content: String,
+    prompt_tokens: Option<i64>,
+    completion_tokens: Option<i64>,
+    // the hidden part of completion_tokens, only reasoning models report it
+    reasoning_tokens: Option<i64>,
+    system_fingerprint: Option<String>,
+    // the tier that processed the request, which may differ from the one asked for
+    service_tier: Option<String>,
+    finish_reason: Option<String>,
+    // the rest of the answers with --choices, the first one is content
+    other_choices: Vec<String>,
+    // choices[0].logprobs.content with --logprobs, empty when the endpoint doesn't send them
+    logprobs: Vec<serde_json::Value>,
+    // when the first token of a streamed answer arrived
+    first_token_at: Option<Instant>,
+    // the tools the model wants called before it answers, with --tools
+    tool_calls: Vec<serde_json::Value>,
+}
+
+impl Answer {
+    // fill in the usage the provider didn't report by counting locally
+    fn into_completion(self, prompt: &str, model: &str, interrupted: bool) -> Completion {
+        Completion {
+            prompt_tokens: self.prompt_tokens.unwrap_or_else(|| count_tokens(prompt, model)),
+            completion_tokens: self.completion_tokens.unwrap_or_else(|| count_tokens(&self.content, model)),
+            content: self.content,
+            reasoning_tokens: self.reasoning_tokens,
+            system_fingerprint: self.system_fingerprint,
+            service_tier: self.service_tier,
+            finish_reason: self.finish_reason,
+            other_choices: self.other_choices,
+            logprobs: self.logprobs,
+            tool_calls: self.tool_calls,
+            interrupted,
+            cached: false,
+            rate_limit: None,
+            latency: None,
+            first_token: None,
+        }
+    }
 }
 
 fn create_message(role: String, content: String) -> Message {
     Message {
         role,
-        content,
+        content: MessageContent::Text(content),
+        tool_calls: None,
+        tool_call_id: None,
     }
 }
 
@@ -57,159 +317,3074 @@ fn create_log(role: String, content: String, tokens: i64) -> Log {
 }
 
 
-fn main() -> Result<(), Error> {
-    
-    let dotenv_path = match current_exe() {
-        Ok(mut path) => {
-            path.pop(); // This will remove the binary name from the path.
-            path.push(".env"); // This will append the .env file name to the path.
-            path
-        },
-        Err(e) => {
-            eprintln!("Failed to get current binary path: {}", e);
-            PathBuf::from(".env") // Fallback to looking for .env in the current directory.
-        },
+// count tokens locally with the BPE encoding used by the model
+fn count_tokens(text: &str, model: &str) -> i64 {
+    let bpe = if model.starts_with("gpt-4o")
+        || model.starts_with("gpt-4.1")
+        || model.starts_with("gpt-5")
+        || model.starts_with("o1")
+        || model.starts_with("o3")
+        || model.starts_with("o4")
+    {
+        o200k_base_singleton()
+    } else {
+        cl100k_base_singleton()
     };
-    
-    dotenv::from_path(dotenv_path.as_path()).ok();
-    
-    let args = CliArgs::parse();
+    bpe.encode_with_special_tokens(text).len() as i64
+}
 
-    // get OPENAI_API_KEY from environment variable
-    let key = "OPENAI_API_KEY";
-    let openai_api_key = env::var(key).expect(&format!("{} not set", key));
-    let openai_api_base = env::var("OPENAI_API_BASE").unwrap_or_else(|_| String::from("https://api.openai.com/v1/chat/completions/"));
-    // get the prompt from the user
-    let prompt = args.prompt.join(" ");
+// estimate the prompt size of the messages, each one costs a few tokens on top of its content
+fn messages_token_estimate(messages: &[Message], model: &str) -> i64 {
+    messages
+        .iter()
+        .map(|message| count_tokens(&message.content.text(), model) + TOKENS_PER_MESSAGE)
+        .sum::<i64>()
+        + 3
+}
 
-    // Get the model from the CLI argument, environment variable, or use the default value
-    let model = args
-        .model
-        .or_else(|| env::var("CHATGPT_CLI_MODEL").ok())
-        .unwrap_or_else(|| "gpt-3.5-turbo".to_string());
+// drop the oldest history until the messages fit the budget, the first `pinned` messages (the system prompt
+// and context) and the prompt always stay. summaries and tool results in the history are system messages too,
+// but they go like the rest of it. returns how many turns were dropped
+fn fit_to_budget(messages: &mut Vec<Message>, pinned: usize, model: &str, context_tokens: i64) -> usize {
+    let mut dropped = 0;
+    while messages_token_estimate(messages, model) > context_tokens && pinned < messages.len() - 1 {
+        messages.remove(pinned);
+        // take the tool results and the answer with their question, the history shouldn't start with an answer
+        while pinned < messages.len() - 1 && messages[pinned].role != "user" {
+            messages.remove(pinned);
+        }
+        dropped += 1;
+    }
+    dropped
+}
+
+// where the deltas of a streamed answer are shown as they arrive
+#[derive(Clone, Copy, PartialEq)]
+enum Echo {
+    Off,
+    Stdout,
+    // for watching while stdout goes to a file or a pipe, which gets the whole answer at the end
+    Stderr,
+}
+
+// add a read to the pending bytes and take the lines it completes, a line may be split across reads.
+// the bytes are kept until then, a character split across reads is whole again by the end of its line:
+// \n never occurs inside a multi-byte UTF-8 sequence, so cutting at it can't split one
+fn take_lines(pending: &mut Vec<u8>, chunk: &[u8]) -> Vec<String> {
+    pending.extend_from_slice(chunk);
+    let mut lines = vec![];
+    while let Some(pos) = pending.iter().position(|&b| b == b'\n') {
+        let line: Vec<u8> = pending.drain(..=pos).collect();
+        lines.push(String::from_utf8_lossy(&line).into_owned());
+    }
+    lines
+}
+
+// the text of a stream event, OpenAI and Anthropic put it in different places
+fn delta_content(chunk: &serde_json::Value) -> Option<&str> {
+    chunk["choices"][0]["delta"]["content"]
+        .as_str()
+        .or_else(|| chunk["delta"]["text"].as_str())
+}
+
+// read the SSE body chunk by chunk, printing each delta as it arrives
+async fn read_stream(
+    mut response: Response,
+    spinner: &ProgressBar,
+    echo: Echo,
+    debug: bool,
+    answer: &mut Answer,
+) -> Result<(), AskError> {
+    let mut pending: Vec<u8> = vec![];
+
+    while let Some(chunk) = response.chunk().await? {
+        // only handle complete lines
+        for line in take_lines(&mut pending, &chunk) {
+            if debug && !line.trim().is_empty() {
+                debug_log("stream", line.trim());
+            }
+            let data = match line.trim().strip_prefix("data:") {
+                Some(data) => data.trim(),
+                None => continue,
+            };
+
+            if data == "[DONE]" {
+                return Ok(());
+            }
+
+            let chunk: serde_json::Value = serde_json::from_str(data)?;
+            if let Some(message) = chunk["error"]["message"].as_str() {
+                return Err(AskError::Api(message.to_string()));
+            }
+            // OpenAI sends usage in a final chunk, Anthropic splits it across message_start and message_delta
+            if let Some(tokens) = chunk["usage"]["prompt_tokens"]
+                .as_i64()
+                .or_else(|| chunk["message"]["usage"]["input_tokens"].as_i64())
+            {
+                answer.prompt_tokens = Some(tokens);
+            }
+            if let Some(tokens) = chunk["usage"]["completion_tokens"]
+                .as_i64()
+                .or_else(|| chunk["usage"]["output_tokens"].as_i64())
+            {
+                answer.completion_tokens = Some(tokens);
+            }
+            if let Some(tokens) = chunk["usage"]["completion_tokens_details"]["reasoning_tokens"].as_i64() {
+                answer.reasoning_tokens = Some(tokens);
+            }
+            if let Some(fingerprint) = chunk["system_fingerprint"].as_str() {
+                answer.system_fingerprint = Some(fingerprint.to_string());
+            }
+            if let Some(tier) = chunk["service_tier"].as_str() {
+                answer.service_tier = Some(tier.to_string());
+            }
+            if let Some(reason) = chunk["choices"][0]["finish_reason"]
+                .as_str()
+                .or_else(|| chunk["delta"]["stop_reason"].as_str())
+            {
+                answer.finish_reason = Some(reason.to_string());
+            }
+            if let Some(content) = delta_content(&chunk) {
+                // stop the spinner as soon as the first token lands
+                if !spinner.is_finished() {
+                    spinner.finish_and_clear();
+                }
+                answer.first_token_at.get_or_insert_with(Instant::now);
+                match echo {
+                    Echo::Off => {}
+                    Echo::Stdout => {
+                        print!("{}", content);
+                        io::stdout().flush()?;
+                    }
+                    Echo::Stderr => {
+                        eprint!("{}", content);
+                        io::stderr().flush()?;
+                    }
+                }
+                answer.content.push_str(content);
+            }
+            if let Some(tokens) = chunk["choices"][0]["logprobs"]["content"].as_array() {
+                answer.logprobs.extend(tokens.iter().cloned());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// ~/.ask, without a home directory (e.g. in CI) the directory of ASK_LOG_PATH or a temporary one
+fn ask_dir() -> PathBuf {
+    if let Some(home) = dirs::home_dir() {
+        return home.join(".ask");
+    }
+
+    let fallback = env::var_os("ASK_LOG_PATH")
+        .and_then(|path| PathBuf::from(path).parent().map(Path::to_path_buf))
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or_else(|| env::temp_dir().join("ask"));
+    // it's asked for in several places, say it once
+    static WARNING: Once = Once::new();
+    WARNING.call_once(|| eprintln!("Warning: no home directory found, keeping files in {}", fallback.display()));
+    fallback
+}
+
+// sessions live in ~/.ask/sessions/<name>.json (or .yaml), so the name must stay a plain file name
+fn session_path(ask_dir: &Path, name: &str, format: LogFormat) -> Result<PathBuf, AskError> {
+    if name.is_empty() || name.contains('/') || name.contains('\\') || name.contains("..") {
+        return Err(AskError::Config(format!("invalid session name: {}", name)));
+    }
+    Ok(log_file(&ask_dir.join("sessions"), name, format))
+}
+
+// <name>.json or <name>.yaml by the log format, unless only a file in the other format exists yet
+fn log_file(dir: &Path, name: &str, format: LogFormat) -> PathBuf {
+    let path = dir.join(format!("{}.{}", name, format.extension()));
+    let other = match format {
+        LogFormat::Json => LogFormat::Yaml,
+        LogFormat::Yaml => LogFormat::Json,
+    };
+    let other_path = dir.join(format!("{}.{}", name, other.extension()));
+    if !path.exists() && other_path.exists() {
+        other_path
+    } else {
+        path
+    }
+}
+
+// a chatlog ending in .yaml or .yml is YAML, anything else JSON
+fn is_yaml(chatlog_path: &Path) -> bool {
+    chatlog_path
+        .extension()
+        .is_some_and(|extension| extension == "yaml" || extension == "yml")
+}
+
+fn list_sessions(ask_dir: &Path) -> Result<Vec<String>, AskError> {
+    let entries = match fs::read_dir(ask_dir.join("sessions")) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut names = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        // a session that was only given a title so far has just the meta file
+        let name = file_name
+            .strip_suffix(".meta.json")
+            .or_else(|| file_name.strip_suffix(".json"))
+            .or_else(|| file_name.strip_suffix(".yaml"));
+        if let Some(name) = name {
+            names.push(name.to_string());
+        }
+    }
+    names.sort();
+    names.dedup();
+    Ok(names)
+}
+
+// the context window of well known models, more specific prefixes first
+fn context_window(model: &str) -> Option<i64> {
+    let windows: [(&str, i64); 12] = [
+        ("gpt-4o", 128_000),
+        ("gpt-4.1", 1_047_576),
+        ("gpt-4-turbo", 128_000),
+        ("gpt-4-32k", 32_768),
+        ("gpt-4", 8_192),
+        ("gpt-3.5-turbo", 16_385),
+        ("gpt-5", 400_000),
+        ("o1-mini", 128_000),
+        ("o1", 200_000),
+        ("o3", 200_000),
+        ("o4", 200_000),
+        ("claude", 200_000),
+    ];
+    windows
+        .iter()
+        .find(|(prefix, _)| model.starts_with(prefix))
+        .map(|(_, window)| *window)
+}
+
+// models known to be text only, anything else gets to decide for itself
+fn supports_images(model: &str) -> bool {
+    let text_only = ["gpt-3.5", "gpt-4-0", "gpt-4-32k", "o1-mini", "o3-mini"];
+    model != "gpt-4" && !text_only.iter().any(|prefix| model.starts_with(prefix))
+}
+
+// what a model accepts where it differs from the usual chat models
+#[derive(Clone, Copy)]
+struct Capabilities {
+    // the limit is max_completion_tokens, max_tokens is rejected
+    max_completion_tokens: bool,
+    // temperature, top_p, the penalties and logprobs
+    sampling: bool,
+}
+
+// the reasoning models, more specific prefixes first
+fn capabilities(model: &str) -> Capabilities {
+    let reasoning = Capabilities { max_completion_tokens: true, sampling: false };
+    let models = [
+        ("gpt-5-chat", Capabilities { max_completion_tokens: true, sampling: true }),
+        ("gpt-5", reasoning),
+        ("o1", reasoning),
+        ("o3", reasoning),
+        ("o4", reasoning),
+    ];
+    models
+        .into_iter()
+        .find(|(prefix, _)| model.starts_with(prefix))
+        .map(|(_, capabilities)| capabilities)
+        .unwrap_or(Capabilities { max_completion_tokens: false, sampling: true })
+}
+
+// rename and leave out what the model would reject with a 400, returning the names of what was left out
+fn adapt_to_model(request: &mut OpenAIRequest) -> Vec<&'static str> {
+    let capabilities = capabilities(&request.model);
+    if capabilities.max_completion_tokens {
+        request.max_completion_tokens = request.max_tokens.take();
+    }
+    let mut dropped = vec![];
+    if !capabilities.sampling {
+        if request.temperature.take().is_some() {
+            dropped.push("temperature");
+        }
+        if request.top_p.take().is_some() {
+            dropped.push("top_p");
+        }
+        if request.presence_penalty.take().is_some() {
+            dropped.push("presence_penalty");
+        }
+        if request.frequency_penalty.take().is_some() {
+            dropped.push("frequency_penalty");
+        }
+        if request.logprobs.take().is_some() {
+            request.top_logprobs = None;
+            dropped.push("logprobs");
+        }
+    }
+    dropped
+}
+
+// make sure a sampling parameter is within the range the API accepts
+fn check_range(name: &str, value: Option<f32>, min: f32, max: f32) -> Result<(), AskError> {
+    match value {
+        Some(value) if !(min..=max).contains(&value) => Err(AskError::Config(format!(
+            "{} must be between {} and {}, got {}",
+            name, min, max, value
+        ))),
+        _ => Ok(()),
+    }
+}
+
+// check the endpoint is an http(s) URL, a bare host gets the default endpoint path
+fn normalize_api_base(name: &str, api_base: &str, default_api_base: &str) -> Result<String, AskError> {
+    let mut url = Url::parse(api_base.trim())
+        .map_err(|e| AskError::Config(format!("{} is not a valid URL ({}): {}", name, e, api_base)))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(AskError::Config(format!(
+            "{} must be an http or https URL, got {}",
+            name, api_base
+        )));
+    }
+
+    let path = url.path().trim_end_matches('/').to_string();
+    let default_path = Url::parse(default_api_base)
+        .map(|default| default.path().to_string())
+        .unwrap_or_default();
+    if path.is_empty() {
+        url.set_path(&default_path);
+    } else if path == "/v1" {
+        // a common mistake, the base of the API rather than the endpoint
+        url.set_path(&format!("{}{}", path, default_path.trim_start_matches("/v1")));
+    } else {
+        url.set_path(&path);
+    }
+    Ok(url.to_string())
+}
+
+// Azure names the deployment in the URL, e.g.
+// https://name.openai.azure.com/openai/deployments/<deployment>/chat/completions?api-version=...
+fn azure_url(endpoint: &str, deployment: &str) -> Result<String, AskError> {
+    let mut url = Url::parse(endpoint.trim()).map_err(|e| {
+        AskError::Config(format!("AZURE_OPENAI_ENDPOINT is not a valid URL ({}): {}", e, endpoint))
+    })?;
+    // a full deployment URL is used as it is
+    if !url.path().contains("/openai/deployments/") {
+        let path = format!("{}/openai/deployments/{}/chat/completions", url.path().trim_end_matches('/'), deployment);
+        url.set_path(&path);
+    }
+    if !url.query_pairs().any(|(name, _)| name == "api-version") {
+        let version = env::var("AZURE_OPENAI_API_VERSION").unwrap_or_else(|_| AZURE_API_VERSION.to_string());
+        url.query_pairs_mut().append_pair("api-version", &version);
+    }
+    Ok(url.to_string())
+}
+
+// print a debugging detail to stderr, away from the answer
+fn debug_log(label: &str, text: &str) {
+    eprintln!("[debug] {}: {}", label, text);
+}
+
+// the request headers with the API key hidden
+fn redacted_headers(headers: &HeaderMap) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| {
+            let value = if name == AUTHORIZATION || name == "x-api-key" || name == "api-key" {
+                "<redacted>"
+            } else {
+                value.to_str().unwrap_or("<binary>")
+            };
+            format!("{}: {}", name, value)
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+// split a "Name: Value" header from the command line
+fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue), AskError> {
+    let invalid = |reason: &str| AskError::Config(format!("invalid --header \"{}\": {}", header, reason));
+    let (name, value) = header.split_once(':').ok_or_else(|| invalid("expected \"Name: Value\""))?;
+    let name = HeaderName::from_bytes(name.trim().as_bytes()).map_err(|_| invalid("bad header name"))?;
+    let value = HeaderValue::from_str(value.trim()).map_err(|_| invalid("bad header value"))?;
+    Ok((name, value))
+}
+
+// the HTTP client, going through --proxy, ALL_PROXY or HTTPS_PROXY when one is set
+fn build_client(proxy: Option<&str>) -> Result<Client, AskError> {
+    let proxy = proxy.map(str::to_string).or_else(|| {
+        ["ALL_PROXY", "all_proxy", "HTTPS_PROXY", "https_proxy"]
+            .iter()
+            .find_map(|name| env::var(name).ok().filter(|x| !x.is_empty()))
+    });
+    let Some(proxy) = proxy else {
+        return Ok(Client::new());
+    };
+
+    let invalid = |reason: String| AskError::Config(format!("invalid proxy URL {}: {}", proxy, reason));
+    let url = Url::parse(&proxy).map_err(|e| invalid(e.to_string()))?;
+    if !["http", "https", "socks5", "socks5h"].contains(&url.scheme()) {
+        return Err(invalid("the scheme must be http, https, socks5 or socks5h".to_string()));
+    }
+    // NO_PROXY still applies, so local servers are reached directly
+    let proxy = Proxy::all(url.as_str())
+        .map_err(|e| invalid(e.to_string()))?
+        .no_proxy(NoProxy::from_env());
+    Client::builder()
+        .proxy(proxy)
+        .build()
+        .map_err(|e| AskError::Config(format!("could not set up the HTTP client: {}", e)))
+}
+
+// whether the endpoint is on this machine, where no API key is needed
+fn is_local_url(api_base: &str) -> bool {
+    match Url::parse(api_base).ok().and_then(|url| url.host().map(|host| host.to_owned())) {
+        Some(Host::Domain(domain)) => domain == "localhost",
+        Some(Host::Ipv4(ip)) => ip.is_loopback() || ip.is_unspecified(),
+        Some(Host::Ipv6(ip)) => ip.is_loopback() || ip.is_unspecified(),
+        None => false,
+    }
+}
+
+// a rejected key is an error of its own, so scripts can tell it apart from other API errors.
+// key_name is the variable the key came from, for the hint
+pub async fn check_auth(response: Response, key_name: &str) -> Result<Response, AskError> {
+    let status = response.status();
+    if status != StatusCode::UNAUTHORIZED && status != StatusCode::FORBIDDEN {
+        return Ok(response);
+    }
+    let body: serde_json::Value = response.json().await.unwrap_or_default();
+    let message = body["error"]["message"].as_str().unwrap_or("no details");
+    Err(AskError::Auth(format!(
+        "{}: {}\nCheck {}, it should hold the whole key for this endpoint without quotes",
+        status, message, key_name
+    )))
+}
+
+// the key without the whitespace a copy and paste picks up, warning when it doesn't look like a key
+// of the provider. only the official endpoints are checked, gateways and proxies have keys of their own
+fn validate_api_key(key_name: &str, api_key: &str, expected_prefix: Option<&str>) -> String {
+    let trimmed = api_key.trim();
+    if trimmed.len() != api_key.len() {
+        eprintln!("Warning: {} has whitespace around it, ignoring it", key_name);
+    }
+    if let Some(prefix) = expected_prefix {
+        if !trimmed.starts_with(prefix) {
+            eprintln!("Warning: {} doesn't start with {}, is it the right key?", key_name, prefix);
+        } else if trimmed.len() < MIN_API_KEY_LEN {
+            eprintln!("Warning: {} is only {} characters, it may be cut off", key_name, trimmed.len());
+        }
+    }
+    trimmed.to_string()
+}
+
+// a connection that failed or was reset before the response came, worth trying again.
+// a timeout isn't, the request may have been running on the server all along
+fn is_transport_error(e: &reqwest::Error) -> bool {
+    !e.is_timeout() && (e.is_connect() || e.is_request())
+}
+
+// send the request, retrying connection failures, rate limits and server errors with exponential backoff.
+// also returns when the last attempt was sent, the latency shouldn't include the waits before it
+async fn send_with_retries(
+    send: impl Fn() -> RequestBuilder,
+    spinner: &ProgressBar,
+    key_name: &str,
+) -> Result<(Response, Instant), AskError> {
+    let max_retries: u32 = env::var("ASK_MAX_RETRIES")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(DEFAULT_MAX_RETRIES);
+    let mut delay = Duration::from_secs(1);
+    let mut attempt = 0;
+    let mut transport_attempt = 0;
+
+    loop {
+        let sent = Instant::now();
+        let response = match send().send().await {
+            Ok(response) => check_auth(response, key_name).await?,
+            Err(e) if is_transport_error(&e) && transport_attempt < MAX_TRANSPORT_RETRIES => {
+                transport_attempt += 1;
+                spinner.set_message(format!(
+                    "connection failed, retrying in {}s (attempt {}/{})",
+                    delay.as_secs(),
+                    transport_attempt,
+                    MAX_TRANSPORT_RETRIES
+                ));
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                continue;
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let status = response.status();
+        if status != StatusCode::TOO_MANY_REQUESTS && !status.is_server_error() {
+            return Ok((response, sent));
+        }
+
+        if attempt >= max_retries {
+            let body: serde_json::Value = response.json().await.unwrap_or_default();
+            let message = body["error"]["message"].as_str().unwrap_or("no details");
+            return Err(AskError::Api(format!(
+                "{} after {} retries: {}",
+                status, max_retries, message
+            )));
+        }
+
+        // the server may tell us how long to wait, or when the rate limit that ran out resets
+        let wait = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|x| x.to_str().ok())
+            .and_then(|x| x.trim().parse().ok())
+            .map(Duration::from_secs)
+            .or_else(|| ratelimit::from_headers(response.headers()).and_then(|limit| limit.wait()))
+            .unwrap_or(delay);
+
+        attempt += 1;
+        spinner.set_message(format!(
+            "{}, retrying in {}s (attempt {}/{})",
+            status,
+            wait.as_secs(),
+            attempt,
+            max_retries
+        ));
+        tokio::time::sleep(wait).await;
+        delay *= 2;
+    }
+}
 
+// the answer of a chat completions response, explaining why when there is no text
+fn parse_answer(response: &serde_json::Value) -> Result<Answer, AskError> {
+    let choice = &response["choices"][0];
+    if choice.is_null() {
+        return Err(AskError::Response("missing choices[0]".to_string()));
+    }
+    let finish_reason = choice["finish_reason"].as_str().map(str::to_string);
+
+    let tool_calls = choice["message"]["tool_calls"].as_array().cloned().unwrap_or_default();
+
+    let content = match choice["message"]["content"].as_str() {
+        Some(content) => content.to_string(),
+        // asking for a tool call usually comes without text
+        None if !tool_calls.is_empty() => String::new(),
+        None => {
+            return Err(match (choice["message"]["refusal"].as_str(), finish_reason.as_deref()) {
+                (Some(refusal), _) => AskError::Api(format!("the model refused: {}", refusal)),
+                (None, Some("content_filter")) => {
+                    AskError::Api("the answer was blocked by the content filter".to_string())
+                }
+                (None, Some(reason)) => AskError::Response(format!("the answer has no text (finish_reason: {})", reason)),
+                (None, None) => AskError::Response("missing choices[0].message.content".to_string()),
+            })
+        }
+    };
+
+    Ok(Answer {
+        content,
+        prompt_tokens: response["usage"]["prompt_tokens"].as_i64(),
+        completion_tokens: response["usage"]["completion_tokens"].as_i64(),
+        reasoning_tokens: response["usage"]["completion_tokens_details"]["reasoning_tokens"].as_i64(),
+        system_fingerprint: response["system_fingerprint"].as_str().map(str::to_string),
+        service_tier: response["service_tier"].as_str().map(str::to_string),
+        finish_reason,
+        other_choices: response["choices"]
+            .as_array()
+            .map(|choices| {
+                choices[1..]
+                    .iter()
+                    .filter_map(|choice| choice["message"]["content"].as_str())
+                    .map(str::to_string)
+                    .collect()
+            })
+            .unwrap_or_default(),
+        logprobs: choice["logprobs"]["content"].as_array().cloned().unwrap_or_default(),
+        first_token_at: None,
+        tool_calls,
+    })
+}
+
+// add the start of the raw body to a response error, e.g. when a proxy answers with an HTML page
+fn with_raw_body(e: AskError, body: &str) -> AskError {
+    match e {
+        AskError::Response(message) => {
+            let start: String = body.chars().take(500).collect();
+            AskError::Response(format!("{}, the response was:\n{}", message, start))
+        }
+        e => e,
+    }
+}
+
+// with --response-format json the answer must parse, otherwise show what came back instead
+fn check_json_answer(content: &str) -> Result<(), AskError> {
+    match serde_json::from_str::<serde_json::Value>(content) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(AskError::Response(format!("the answer is not valid JSON ({}):\n{}", e, content))),
+    }
+}
+
+// remove the chatlog file, it's fine if it was never created
+fn clear_chatlog(chatlog_path: &Path) -> Result<(), AskError> {
+    // the title belonged to the old conversation
+    session::remove_meta(chatlog_path)?;
+    match fs::remove_file(chatlog_path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+// everything needed to send a conversation to the provider
+struct Backend {
+    client: Client,
+    provider: Provider,
+    model: String,
+    api_base: String,
+    headers: HeaderMap,
+    // the environment variable with the key, e.g. OPENAI_API_KEY
+    key_name: &'static str,
+    timeout_secs: u64,
+    temperature: Option<f32>,
+    presence_penalty: Option<f32>,
+    frequency_penalty: Option<f32>,
+    max_tokens: Option<i64>,
+    context_tokens: i64,
+    project_context: Option<String>,
+    spinner_message: String,
+    // set with --cache or ASK_CACHE=1 for requests that would get the same answer again
+    cache: Option<ResponseCache>,
+    // --mock or ASK_MOCK_RESPONSE, answers every request without calling the API
+    mock_response: Option<String>,
+    // renamed roles from the config file for servers that reject some of them, empty for most
+    roles: HashMap<String, String>,
+    role_prefix: Option<String>,
+    // sent as the end user for per-user attribution when a team shares a key
+    user_id: Option<String>,
+    // commands from the config file the model may call with --tools
+    tools: HashMap<String, tools::ToolConfig>,
+    debug: bool,
+}
+
+impl Backend {
+    // a request to the endpoint with the headers, a timeout of 0 waits as long as it takes
+    fn post(&self, body: String) -> RequestBuilder {
+        let request = self.client.post(&self.api_base).headers(self.headers.clone()).body(body);
+        match self.timeout_secs {
+            0 => request,
+            secs => request.timeout(Duration::from_secs(secs)),
+        }
+    }
+}
+
+// the answer to a prompt and the tokens it used
+#[derive(Serialize, Deserialize)]
+struct Completion {
+    content: String,
+    prompt_tokens: i64,
+    completion_tokens: i64,
+    #[serde(default)]
+    reasoning_tokens: Option<i64>,
+    // identifies the backend configuration, for telling apart answers to the same seed
+    system_fingerprint: Option<String>,
+    #[serde(default)]
+    service_tier: Option<String>,
+    // why the model stopped, anything but a natural stop means the answer may be incomplete
+    finish_reason: Option<String>,
+    other_choices: Vec<String>,
+    logprobs: Vec<serde_json::Value>,
+    #[serde(default)]
+    tool_calls: Vec<serde_json::Value>,
+    // Ctrl-C stopped the stream, so the content is only partial
+    #[serde(skip)]
+    interrupted: bool,
+    // answered from the cache, without a request
+    #[serde(skip)]
+    cached: bool,
+    #[serde(skip)]
+    rate_limit: Option<RateLimit>,
+    // from sending the request to the whole answer, and to the first token when streaming
+    #[serde(skip)]
+    latency: Option<Duration>,
+    #[serde(skip)]
+    first_token: Option<Duration>,
+}
+
+// set while a streamed answer is being read, so Ctrl-C saves what arrived instead of exiting
+static STREAMING: AtomicBool = AtomicBool::new(false);
+static INTERRUPT: Notify = Notify::const_new();
+
+// Ctrl-C exits with 130, unless an answer is streaming, then the stream is stopped first
+fn handle_ctrl_c() {
+    tokio::spawn(async {
+        while signal::ctrl_c().await.is_ok() {
+            if STREAMING.load(Ordering::SeqCst) {
+                INTERRUPT.notify_one();
+            } else {
+                eprintln!();
+                process::exit(130);
+            }
+        }
+    });
+}
 
-    // load the chatlog for this terminal window
-    let chatlog_path = dirs::home_dir()
-    .expect("Failed to get home directory")
-    .join(".ask/ask_log.json");
+// bumped when the chatlog format changes, older binaries refuse newer chatlogs instead of losing entries
+const CHATLOG_VERSION: u64 = 1;
 
+// the chatlog on disk, before version 1 it was only the array of entries
+#[derive(Serialize, Deserialize)]
+struct ChatlogFile<T> {
+    version: u64,
+    entries: T,
+}
 
+// read the chatlog from disk, creating it if it doesn't exist yet
+fn load_chatlog(chatlog_path: &Path) -> Result<Vec<Log>, AskError> {
     fs::create_dir_all(chatlog_path.parent().unwrap())?;
 
     let mut file = OpenOptions::new()
         .create(true) // create the file if it doesn't exist
         .append(true) // don't overwrite the contents
         .read(true)
-        .open(&chatlog_path)
-        .unwrap();
+        .open(chatlog_path)?;
 
     let mut chatlog_text = String::new();
     file.read_to_string(&mut chatlog_text)?;
 
-    // get the messages from the chatlog. limit the total number of tokens to 3000
-    let mut total_tokens: i64 = 0;
-    let mut messages: Vec<Message> = vec![];
-    let mut chatlog: Vec<Log> = vec![];
-
-    if !chatlog_text.is_empty() {
-        chatlog = serde_json::from_str(&chatlog_text)?;
-        for log in chatlog.iter().rev() {
-            if total_tokens + log.tokens > MAX_TOKENS {
-                continue;
-            }
+    if chatlog_text.is_empty() {
+        return Ok(vec![]);
+    }
 
-            total_tokens += log.tokens;
-            messages.push(create_message(log.role.clone(), log.content.clone()));
+    // a legacy bare array is read as is and upgraded on the next save
+    let value = if is_yaml(chatlog_path) {
+        serde_yaml::from_str::<serde_json::Value>(&chatlog_text).map_err(AskError::from)
+    } else {
+        serde_json::from_str::<serde_json::Value>(&chatlog_text).map_err(AskError::from)
+    };
+    let parsed = value.and_then(|value| {
+        let entries = match value["version"].as_u64() {
+            Some(version) if version > CHATLOG_VERSION => return Ok(Err(version)),
+            Some(_) => serde_json::from_value::<ChatlogFile<Vec<Log>>>(value).map(|file| file.entries),
+            None => serde_json::from_value::<Vec<Log>>(value),
+        };
+        Ok(Ok(entries?))
+    });
 
+    // keep a broken chatlog around for inspection and start a fresh one
+    match parsed {
+        Ok(Ok(chatlog)) => Ok(chatlog),
+        Ok(Err(version)) => Err(AskError::Config(format!(
+            "{} was written by a newer version of ask (chatlog version {}, this one reads up to {}), please upgrade",
+            chatlog_path.display(),
+            version,
+            CHATLOG_VERSION
+        ))),
+        Err(e) => {
+            let backup_path = path_with_suffix(chatlog_path, ".corrupt");
+            fs::rename(chatlog_path, &backup_path)?;
+            eprintln!(
+                "Warning: {} could not be read ({}), moved it to {} and started a new conversation",
+                chatlog_path.display(),
+                e,
+                backup_path.display()
+            );
+            Ok(vec![])
         }
     }
+}
 
-    messages = messages.into_iter().rev().collect();
+// e.g. ask_log.json -> ask_log.json.corrupt
+fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
 
-    messages.push(create_message("user".to_string(), prompt.clone()));
+// an advisory lock next to the chatlog, held from reading it until the answer is saved,
+// so runs on the same conversation in two terminals don't overwrite each other's turns
+fn lock_chatlog(chatlog_path: &Path) -> Result<File, AskError> {
+    if let Some(parent) = chatlog_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let lock = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path_with_suffix(chatlog_path, ".lock"))?;
+    if lock.try_lock_exclusive().is_err() {
+        eprintln!("Waiting for another ask to finish with {}", chatlog_path.display());
+        lock.lock_exclusive()?;
+    }
+    Ok(lock)
+}
 
+// write to a temporary file next to the chatlog and rename it over the old one,
+// so an interrupted write never leaves a truncated chatlog behind
+fn save_chatlog(chatlog_path: &Path, chatlog: &[Log]) -> Result<(), AskError> {
+    let file = ChatlogFile { version: CHATLOG_VERSION, entries: chatlog };
+    let chatlog_text = if is_yaml(chatlog_path) {
+        serde_yaml::to_string(&file)?
+    } else {
+        serde_json::to_string(&file)?
+    };
+    let temp_path = path_with_suffix(chatlog_path, &format!(".{}.tmp", process::id()));
+    fs::write(&temp_path, chatlog_text)?;
+    if let Err(e) = fs::rename(&temp_path, chatlog_path) {
+        fs::remove_file(&temp_path).ok();
+        return Err(e.into());
+    }
+    Ok(())
+}
 
+// every option also works after a subcommand, e.g. ask history 10 --session work, so they are all global.
+// the prompt is the only positional argument, and --chat-only of the models subcommand is its own.
+// a global option can't conflict with the prompt, run() checks those
+fn cli_command() -> clap::Command {
+    CliArgs::command().mut_args(|arg| {
+        if arg.is_positional() || arg.get_id() == "chat_only" {
+            arg
+        } else {
+            arg.global(true)
+        }
+    })
+}
 
-    let client = Client::new();
-    let data = OpenAIRequest {     // send the POST request to OpenAI
-        model: model.to_string(),
-        messages,
+fn parse_args(argv: &[OsString]) -> Result<CliArgs, clap::Error> {
+    let matches = cli_command().try_get_matches_from(argv)?;
+    let mut args = CliArgs::from_arg_matches(&matches)?;
+    // --user and --assistant after chat are counted there
+    args.script = match matches.subcommand() {
+        Some(("chat", chat)) => scripted_turns(chat),
+        _ => scripted_turns(&matches),
     };
+    args.apply_command();
+    Ok(args)
+}
 
-    let mut headers = HeaderMap::new();
-    headers.insert(
-        AUTHORIZATION,
-        format!("Bearer {}", openai_api_key).parse().unwrap(),
-    );
-    headers.insert(CONTENT_TYPE, "application/json".parse().unwrap());
-    let json_data = serde_json::to_string(&data)?;
-    let timeout_secs = env::var("CHATGPT_CLI_REQUEST_TIMEOUT_SECS")
-        .ok()
-        .and_then(|x| x.parse().ok())
-        .unwrap_or(DEFAULT_TIMEOUT_SECS); // default value of 120 seconds
-    // Create a spinner
-    let spinner = ProgressBar::new_spinner();
-    spinner.set_style(ProgressStyle::default_spinner());
+// the --user and --assistant messages in the order they were given on the command line
+fn scripted_turns(matches: &ArgMatches) -> Vec<(String, String)> {
+    let mut turns: Vec<(usize, String, String)> = vec![];
+    for role in ["user", "assistant"] {
+        if let (Some(indices), Some(values)) = (matches.indices_of(role), matches.get_many::<String>(role)) {
+            turns.extend(indices.zip(values).map(|(index, text)| (index, role.to_string(), text.clone())));
+        }
+    }
+    turns.sort_by_key(|(index, _, _)| *index);
+    turns.into_iter().map(|(_, role, text)| (role, text)).collect()
+}
 
-    // Start the spinner
-    spinner.enable_steady_tick(Duration::from_millis(100));
+fn ends_with_exchange(chatlog: &[Log], question: &str, answer: &str) -> bool {
+    match chatlog {
+        [.., asked, answered] => {
+            asked.role == "user"
+                && asked.content == question
+                && answered.role == "assistant"
+                && answered.content == answer
+        }
+        _ => false,
+    }
+}
 
-    let response = client
-        .post(&openai_api_base)
-        .timeout(Duration::from_secs(timeout_secs))
-        .headers(headers)
-        .body(json_data)
-        .send()
-        .unwrap()
-        .json::<serde_json::Value>()
-        .unwrap();
+// remove the last question and answer from the chatlog, and the tool calls between them, returning both
+fn pop_last_exchange(chatlog: &mut Vec<Log>) -> Result<(String, String), AskError> {
+    if chatlog.last().is_none_or(|log| log.role != "assistant") {
+        return Err(AskError::Config("The last message is not an answer, nothing to regenerate".to_string()));
+    }
+    let tool_logs = chatlog[..chatlog.len() - 1].iter().rev().take_while(|log| log.role == "tool").count();
+    if chatlog.len() < tool_logs + 2 || chatlog[chatlog.len() - tool_logs - 2].role != "user" {
+        return Err(AskError::Config("The last answer has no question to regenerate it from".to_string()));
+    }
 
-    // Stop the spinner
-    spinner.finish_and_clear();
+    let answer = chatlog.pop().map(|log| log.content).unwrap_or_default();
+    chatlog.truncate(chatlog.len() - tool_logs);
+    let question = chatlog.pop().map(|log| log.content).unwrap_or_default();
+    Ok((question, answer))
+}
 
-    // if the response is an error, print it and exit
-    match response["error"].as_object() {
-        None => response["error"].clone(),
-        Some(_) => {
-            println!(
-                "Received an error from OpenAI: {}",
-                response["error"]["message"].as_str().unwrap()
-            );
-            return Ok(());
-        }
+// fold the answer to the continue prompt into the answer it continues, leaving out the prompt and tool calls
+fn merge_continuation(chatlog: &mut Vec<Log>, asked_len: usize) {
+    if chatlog.len() <= asked_len {
+        return;
+    }
+    let Some(continuation) = chatlog.pop() else {
+        return;
     };
+    while chatlog.len() > asked_len && chatlog.last().is_some_and(|log| log.role == "tool" || log.role == "user") {
+        chatlog.pop();
+    }
+    if let Some(answer) = chatlog.iter_mut().rev().find(|log| log.role == "assistant") {
+        answer.content.push_str(&continuation.content);
+        answer.tokens += continuation.tokens;
+    }
+}
 
-    let prompt_tokens = response["usage"]["prompt_tokens"].as_i64().unwrap();
-    let answer_tokens = response["usage"]["completion_tokens"].as_i64().unwrap();
-    let answer = response["choices"][0]["message"]["content"]
-        .as_str()
-        .unwrap();
+// the system prompt that was last used in this chatlog
+fn saved_system_prompt(chatlog: &[Log]) -> Option<&str> {
+    chatlog
+        .iter()
+        .rev()
+        .find(|log| log.role == "system")
+        .map(|log| log.content.as_str())
+}
+
+// estimate the tokens of entries saved without a count, so the budget doesn't treat them as free.
+// returns how many were recounted
+fn recount_missing_tokens(chatlog: &mut [Log], model: &str) -> usize {
+    let mut recounted = 0;
+    for log in chatlog.iter_mut().filter(|log| log.tokens == 0 && !log.content.is_empty()) {
+        log.tokens = count_tokens(&log.content, model);
+        recounted += 1;
+    }
+    recounted
+}
 
-    // Show the response from OpenAI
-    println!("{}", answer);
+// the answer the last time this exact question was asked, for --compare
+fn previous_answer<'a>(chatlog: &'a [Log], prompt: &str) -> Option<&'a str> {
+    chatlog
+        .windows(2)
+        .rev()
+        .find(|pair| pair[0].role == "user" && pair[0].content == prompt && pair[1].role == "assistant")
+        .map(|pair| pair[1].content.as_str())
+}
 
-    // save the new messages to the chatlog
-    chatlog.push(create_log("user".to_string(), prompt, prompt_tokens));
-    chatlog.push(create_log("assistant".to_string(), answer.to_string(), answer_tokens));
+// the most recent history that fits in the token budget, oldest first, without gaps and starting with a question.
+// the system prompt is always sent, so it doesn't count against the budget
+fn select_history(chatlog: &[Log], budget: i64) -> Vec<Message> {
+    let mut total_tokens: i64 = 0;
+    let mut messages: Vec<Message> = vec![];
+    let mut roles: Vec<&str> = vec![];
 
+    for log in chatlog.iter().rev() {
+        if log.role == "system" {
+            continue;
+        }
+        if total_tokens + log.tokens > budget {
+            break;
+        }
 
-    // write the chatlog to disk
-    let chatlog_text = serde_json::to_string(&chatlog)?;
-    fs::write(&chatlog_path, chatlog_text)?;
+        total_tokens += log.tokens;
+        // a summary of older turns and the results of tool calls are context for the model, not part of the dialogue
+        let role = if log.role == "summary" || log.role == "tool" { "system" } else { &log.role };
+        messages.push(create_message(role.to_string(), log.content.clone()));
+        roles.push(&log.role);
+    }
 
-    Ok(())
+    // an answer or tool results whose question didn't fit would start the history mid-turn
+    while roles.last().is_some_and(|role| *role == "assistant" || *role == "tool") {
+        roles.pop();
+        messages.pop();
+    }
+    messages.reverse();
+    messages
 }
 
-// get version from Cargo.toml
-#[derive(Parser, Debug)]
-#[clap(version = env!("CARGO_PKG_VERSION"))]
-struct CliArgs {
+// the system prompt, the history that fits in the context budget next to the prompt, and the prompt
+fn build_messages(
+    chatlog: &[Log],
+    system_prompt: Option<&str>,
+    prompt: &str,
+    model: &str,
+    context_tokens: i64,
+) -> Vec<Message> {
+    let mut messages = select_history(chatlog, context_tokens - count_tokens(prompt, model));
+
+    if let Some(system_prompt) = system_prompt {
+        messages.insert(0, create_message("system".to_string(), system_prompt.to_string()));
+    }
+
+    messages.push(create_message("user".to_string(), prompt.to_string()));
+    messages
+}
+
+// rename the roles the config file maps, e.g. system to user, marking the text with the prefix
+fn remap_roles(roles: &HashMap<String, String>, role_prefix: Option<&str>, messages: Vec<Message>) -> Vec<Message> {
+    messages
+        .into_iter()
+        .map(|mut message| {
+            let Some(role) = roles.get(&message.role) else {
+                return message;
+            };
+            if let Some(prefix) = role_prefix {
+                let prefix = prefix.replace("{role}", &message.role);
+                match &mut message.content {
+                    MessageContent::Text(text) => text.insert_str(0, &prefix),
+                    MessageContent::Parts(parts) => parts.insert(0, ContentPart::Text { text: prefix }),
+                }
+            }
+            message.role = role.clone();
+            message
+        })
+        .collect()
+}
+
+// everything a request needs besides the messages, plain values so building one needs no client
+#[derive(Debug, Default)]
+struct RequestSettings {
+    model: String,
+    temperature: Option<f32>,
+    top_p: Option<f32>,
+    presence_penalty: Option<f32>,
+    frequency_penalty: Option<f32>,
+    max_tokens: Option<i64>,
+    seed: Option<i64>,
+    choices: Option<u32>,
+    logprobs: bool,
+    top_logprobs: Option<u32>,
+    stop: Vec<String>,
+    json: bool,
+    user: Option<String>,
+    service_tier: Option<ServiceTier>,
+    tools: Option<Vec<serde_json::Value>>,
+    stream: bool,
+    // Anthropic always reports the usage of a stream, OpenAI only when asked
+    stream_usage: bool,
+    roles: HashMap<String, String>,
+    role_prefix: Option<String>,
+}
+
+impl RequestSettings {
+    // the sampling settings from the backend and the command line
+    fn new(backend: &Backend, args: &CliArgs) -> Self {
+        RequestSettings {
+            model: backend.model.clone(),
+            temperature: backend.temperature,
+            top_p: args.top_p,
+            presence_penalty: backend.presence_penalty,
+            frequency_penalty: backend.frequency_penalty,
+            max_tokens: backend.max_tokens,
+            seed: args.seed,
+            choices: args.choices,
+            logprobs: args.logprobs,
+            top_logprobs: args.top_logprobs,
+            stop: args.stop.clone(),
+            json: args.response_format == Some(AnswerFormat::Json),
+            user: backend.user_id.clone(),
+            service_tier: args.service_tier,
+            tools: args.tools.then(|| tools::definitions(&backend.tools)),
+            stream: args.stream,
+            stream_usage: args.stream && backend.provider != Provider::Anthropic,
+            roles: backend.roles.clone(),
+            role_prefix: backend.role_prefix.clone(),
+        }
+    }
+}
+
+// the request for the messages with the settings
+fn build_request(settings: &RequestSettings, messages: Vec<Message>) -> OpenAIRequest {
+    OpenAIRequest {
+        model: settings.model.clone(),
+        messages: remap_roles(&settings.roles, settings.role_prefix.as_deref(), messages),
+        temperature: settings.temperature,
+        top_p: settings.top_p,
+        presence_penalty: settings.presence_penalty,
+        frequency_penalty: settings.frequency_penalty,
+        max_tokens: settings.max_tokens,
+        max_completion_tokens: None,
+        seed: settings.seed,
+        n: settings.choices.filter(|&n| n > 1),
+        logprobs: settings.logprobs.then_some(true),
+        top_logprobs: settings.top_logprobs,
+        stop: (!settings.stop.is_empty()).then(|| settings.stop.clone()),
+        response_format: settings.json.then(|| ResponseFormat { kind: "json_object".to_string() }),
+        user: settings.user.clone(),
+        service_tier: settings.service_tier,
+        tools: settings.tools.clone(),
+        stream: settings.stream.then_some(true),
+        stream_options: settings.stream_usage.then_some(StreamOptions { include_usage: true }),
+    }
+}
+
+// send the messages and print the answer, None when the API answered with an error
+async fn send_messages(backend: &Backend, args: &CliArgs, messages: Vec<Message>) -> Result<Option<Completion>, AskError> {
+    let prompt = messages.last().map(|message| message.content.text()).unwrap_or_default();
+    let mut data = build_request(&RequestSettings::new(backend, args), messages);
+    if backend.provider != Provider::Anthropic {
+        adapt_to_model(&mut data);
+    }
+
+    let json_data = match backend.provider {
+        Provider::Openai | Provider::Azure => serde_json::to_string(&data)?,
+        Provider::Anthropic => serde_json::to_string(&AnthropicRequest::from(&data))?,
+    };
+
+    if backend.debug {
+        debug_log("endpoint", &backend.api_base);
+        debug_log("headers", &redacted_headers(&backend.headers));
+        debug_log("request", &json_data);
+    }
+
+    // show what would be sent and stop there, before a spinner starts
+    if args.dry_run {
+        let request: serde_json::Value = serde_json::from_str(&json_data)?;
+        let preview = serde_json::json!({
+            "endpoint": backend.api_base,
+            "model": backend.model,
+            "request": request,
+        });
+        println!("{}", serde_json::to_string_pretty(&preview)?);
+        return Ok(None);
+    }
+
+    // Create a spinner, JSON and quiet output stay free of it and so do logs of redirected output
+    let show_spinner = !args.json
+        && !args.quiet
+        && !args.no_output
+        && !args.no_spinner
+        && render::should_use_color()
+        && io::stderr().is_terminal();
+    let spinner = if show_spinner {
+        ProgressBar::new_spinner()
+    } else {
+        ProgressBar::hidden()
+    };
+    spinner.set_style(ProgressStyle::default_spinner());
+    spinner.set_message(backend.spinner_message.replace("{model}", &backend.model));
+
+    // Start the spinner
+    spinner.enable_steady_tick(Duration::from_millis(100));
+
+    // the mock answer stands in for the API, with the usage counted locally
+    if let Some(content) = &backend.mock_response {
+        spinner.finish_and_clear();
+        if !args.json && !args.no_output && !args.compare {
+            print_answer(args, content);
+        }
+        let answer = Answer {
+            content: content.clone(),
+            prompt_tokens: Some(messages_token_estimate(&data.messages, &backend.model)),
+            finish_reason: Some("stop".to_string()),
+            ..Default::default()
+        };
+        return Ok(Some(answer.into_completion(&prompt, &backend.model, false)));
+    }
+
+    // an identical request was answered before, show that answer without spending tokens
+    let cache_key = match &backend.cache {
+        Some(cache) => Some(cache.key(&backend.api_base, &serde_json::from_str(&json_data)?)),
+        None => None,
+    };
+    if let (Some(cache), Some(key)) = (&backend.cache, &cache_key) {
+        if let Some(mut completion) = cache.get(key) {
+            spinner.finish_and_clear();
+            if !args.json && !args.no_output && !args.compare {
+                print_choices(args, &completion.content, &completion.other_choices);
+            }
+            completion.cached = true;
+            return Ok(Some(completion));
+        }
+    }
+    let remember = |completion: &Completion| {
+        if let (Some(cache), Some(key)) = (&backend.cache, &cache_key) {
+            if let Err(e) = cache.put(key, completion) {
+                eprintln!("Warning: could not cache the answer: {}", e);
+            }
+        }
+    };
+
+    let send = || backend.post(json_data.clone());
+    let response = send_with_retries(send, &spinner, backend.key_name).await;
+    let (response, started) = match response {
+        Ok(response) => response,
+        Err(e) => {
+            spinner.finish_and_clear();
+            return Err(e);
+        }
+    };
+
+    if backend.debug {
+        debug_log("status", &response.status().to_string());
+    }
+    let rate_limit = ratelimit::from_headers(response.headers());
+    if let (true, Some(rate_limit)) = (backend.debug, &rate_limit) {
+        debug_log("rate limit", &ratelimit::format(rate_limit));
+    }
+
+    // the whole response as it came, without picking out the answer
+    if args.raw {
+        let body = response.text().await;
+        spinner.finish_and_clear();
+        let body = body?;
+        match serde_json::from_str::<serde_json::Value>(&body) {
+            Ok(response) => println!("{}", serde_json::to_string_pretty(&response)?),
+            Err(_) => println!("{}", body),
+        }
+        return Ok(None);
+    }
+
+    if args.stream && response.status().is_success() {
+        let mut streamed = Answer::default();
+        let echo = if args.json || args.no_output {
+            Echo::Off
+        } else if args.stream_stderr || (!io::stdout().is_terminal() && io::stderr().is_terminal()) {
+            Echo::Stderr
+        } else {
+            Echo::Stdout
+        };
+
+        // stop reading on Ctrl-C but keep what arrived so far
+        let mut interrupted = false;
+        STREAMING.store(true, Ordering::SeqCst);
+        let result = tokio::select! {
+            result = read_stream(response, &spinner, echo, backend.debug, &mut streamed) => result,
+            _ = INTERRUPT.notified() => {
+                interrupted = true;
+                Ok(())
+            }
+        };
+        STREAMING.store(false, Ordering::SeqCst);
+
+        spinner.finish_and_clear();
+        result?;
+        match echo {
+            Echo::Off => {}
+            Echo::Stdout => println!(),
+            Echo::Stderr => {
+                eprintln!();
+                if !streamed.content.is_empty() {
+                    print_answer(args, &streamed.content);
+                }
+            }
+        }
+
+        if streamed.content.is_empty() {
+            if interrupted {
+                process::exit(130);
+            }
+            return Ok(None);
+        }
+
+        if args.response_format == Some(AnswerFormat::Json) && !interrupted {
+            check_json_answer(&streamed.content)?;
+        }
+        let first_token = streamed.first_token_at.map(|at| at.duration_since(started));
+        let mut completion = streamed.into_completion(&prompt, &backend.model, interrupted);
+        if !interrupted {
+            remember(&completion);
+        }
+        completion.rate_limit = rate_limit;
+        completion.latency = Some(started.elapsed());
+        completion.first_token = first_token;
+        return Ok(Some(completion));
+    }
+
+    let body = response.text().await;
+    let latency = started.elapsed();
+
+    // Stop the spinner
+    spinner.finish_and_clear();
+    let body = body?;
+    if backend.debug {
+        debug_log("response", &body);
+    }
+    let response: serde_json::Value =
+        serde_json::from_str(&body).map_err(|e| with_raw_body(AskError::Response(e.to_string()), &body))?;
+    if !response.is_object() {
+        return Err(with_raw_body(AskError::Response("expected a JSON object".to_string()), &body));
+    }
+
+    // an error in the response fails the run, so the exit code tells scripts what went wrong
+    if response["error"].is_object() {
+        let message = response["error"]["message"].as_str().unwrap_or("unknown error");
+        return Err(AskError::Api(message.to_string()));
+    }
+
+    let answer = match backend.provider {
+        Provider::Openai | Provider::Azure => parse_answer(&response),
+        Provider::Anthropic => anthropic::parse_answer(&response),
+    }
+    .map_err(|e| with_raw_body(e, &body))?;
+    if args.response_format == Some(AnswerFormat::Json) {
+        for content in std::iter::once(&answer.content).chain(&answer.other_choices) {
+            check_json_answer(content)?;
+        }
+    }
+
+    // JSON is printed with the metadata once the turn is done, and --compare prints once it's compared.
+    // a request for tool calls has nothing to print yet
+    let calls_tools = !answer.tool_calls.is_empty() && answer.content.is_empty();
+    if !args.json && !args.no_output && !args.compare && !calls_tools {
+        print_choices(args, &answer.content, &answer.other_choices);
+    }
+
+    let mut completion = answer.into_completion(&prompt, &backend.model, false);
+    remember(&completion);
+    completion.rate_limit = rate_limit;
+    completion.latency = Some(latency);
+    Ok(Some(completion))
+}
+
+// the answer, or every choice under a heading with --choices
+fn print_choices(args: &CliArgs, content: &str, other_choices: &[String]) {
+    if other_choices.is_empty() {
+        print_answer(args, content);
+        return;
+    }
+    let choices = std::iter::once(content).chain(other_choices.iter().map(String::as_str));
+    for (index, choice) in choices.enumerate() {
+        println!("--- choice {} ---", index + 1);
+        print_answer(args, choice);
+    }
+}
+
+// show the answer from OpenAI, styling it only when colors are wanted
+fn print_answer(args: &CliArgs, content: &str) {
+    if args.quiet || !render::should_use_color() {
+        println!("{}", content);
+    } else if args.render || env::var("ASK_RENDER").is_ok_and(|x| x == "1") {
+        print!("{}", render::render_markdown(content));
+    } else if (args.wrap || env::var("ASK_WRAP").is_ok_and(|x| x == "1")) && io::stdout().is_terminal() {
+        let width = terminal_size().map(|(Width(width), _)| width as usize).unwrap_or(80);
+        println!("{}", render::highlight_code_blocks(&render::wrap_prose(content, width)));
+    } else {
+        println!("{}", render::highlight_code_blocks(content));
+    }
+}
+
+// put the answer on the clipboard, without a clipboard (e.g. over ssh) only warn
+fn copy_to_clipboard(text: &str) {
+    if let Err(e) = Clipboard::new().and_then(|mut clipboard| clipboard.set_text(text)) {
+        eprintln!("Warning: could not copy the answer to the clipboard: {}", e);
+    }
+}
+
+// send the messages without streaming or printing anything, returning the answer
+async fn complete_quietly(backend: &Backend, messages: Vec<Message>) -> Result<String, AskError> {
+    if let Some(content) = &backend.mock_response {
+        return Ok(content.clone());
+    }
+    let mut data = OpenAIRequest {
+        model: backend.model.to_string(),
+        messages: remap_roles(&backend.roles, backend.role_prefix.as_deref(), messages),
+        temperature: None,
+        top_p: None,
+        presence_penalty: None,
+        frequency_penalty: None,
+        max_tokens: backend.max_tokens,
+        max_completion_tokens: None,
+        seed: None,
+        n: None,
+        logprobs: None,
+        top_logprobs: None,
+        stop: None,
+        response_format: None,
+        user: backend.user_id.clone(),
+        service_tier: None,
+        tools: None,
+        stream: None,
+        stream_options: None,
+    };
+    if backend.provider != Provider::Anthropic {
+        adapt_to_model(&mut data);
+    }
+    let json_data = match backend.provider {
+        Provider::Openai | Provider::Azure => serde_json::to_string(&data)?,
+        Provider::Anthropic => serde_json::to_string(&AnthropicRequest::from(&data))?,
+    };
+
+    let send = || backend.post(json_data.clone());
+    let (response, _) = send_with_retries(send, &ProgressBar::hidden(), backend.key_name).await?;
+    let response: serde_json::Value = response.json().await?;
+    if let Some(message) = response["error"]["message"].as_str() {
+        return Err(AskError::Api(message.to_string()));
+    }
+
+    let answer = match backend.provider {
+        Provider::Openai | Provider::Azure => parse_answer(&response)?,
+        Provider::Anthropic => anthropic::parse_answer(&response)?,
+    };
+    Ok(answer.content)
+}
+
+// when the history no longer fits the context budget, replace the oldest turns with a summary of them
+async fn summarize_history(backend: &Backend, chatlog: &mut Vec<Log>, prompt: &str) -> Result<(), AskError> {
+    let history_tokens: i64 = chatlog.iter().filter(|log| log.role != "system").map(|log| log.tokens).sum();
+    let budget = backend.context_tokens - count_tokens(prompt, &backend.model);
+    if history_tokens <= budget {
+        return Ok(());
+    }
+
+    // keep the recent turns that fit in half the budget, starting at a question
+    let mut recent_tokens = 0;
+    let mut keep_from = chatlog.len();
+    for (index, log) in chatlog.iter().enumerate().rev() {
+        if log.role == "system" {
+            continue;
+        }
+        if recent_tokens + log.tokens > budget / 2 {
+            break;
+        }
+        recent_tokens += log.tokens;
+        keep_from = index;
+    }
+    while keep_from < chatlog.len() && chatlog[keep_from].role == "assistant" {
+        keep_from += 1;
+    }
+
+    let old: Vec<&Log> = chatlog[..keep_from].iter().filter(|log| log.role != "system").collect();
+    if old.len() < 2 {
+        return Ok(());
+    }
+    let transcript = old
+        .iter()
+        .map(|log| format!("{}: {}", log.role, log.content))
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    eprintln!("Summarizing {} earlier messages...", old.len());
+
+    let messages = vec![
+        create_message("system".to_string(), SUMMARY_PROMPT.to_string()),
+        create_message("user".to_string(), transcript),
+    ];
+    let summary = format!(
+        "Summary of the earlier conversation:\n{}",
+        complete_quietly(backend, messages).await?.trim()
+    );
+
+    // the system prompts stay, they are how the chatlog remembers them
+    let recent = chatlog.split_off(keep_from);
+    chatlog.retain(|log| log.role == "system");
+    let tokens = count_tokens(&summary, &backend.model);
+    chatlog.push(create_log("summary".to_string(), summary, tokens));
+    chatlog.extend(recent);
+    Ok(())
+}
+
+// give a session without a title one, based on its first question. failing only costs the title
+async fn ensure_title(backend: &Backend, chatlog_path: &Path, chatlog: &[Log]) {
+    let mut meta = session::load_meta(chatlog_path);
+    if meta.title.is_some() {
+        return;
+    }
+    let Some(question) = chatlog.iter().find(|log| log.role == "user") else {
+        return;
+    };
+
+    let messages = vec![
+        create_message("system".to_string(), TITLE_PROMPT.to_string()),
+        create_message("user".to_string(), question.content.chars().take(2000).collect()),
+    ];
+    let result = match complete_quietly(backend, messages).await {
+        Ok(title) => {
+            meta.title = Some(title.trim().trim_matches('"').to_string());
+            session::save_meta(chatlog_path, &meta)
+        }
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        eprintln!("Warning: could not give the session a title: {}", e);
+    }
+}
+
+// send the prompt with the conversation so far, print the answer and add both to the chatlog.
+// returns whether the answer was cut short by Ctrl-C
+async fn take_turn(
+    backend: &Backend,
+    args: &CliArgs,
+    chatlog: &mut Vec<Log>,
+    system_prompt: Option<&str>,
+    prompt: &str,
+    previous: Option<&str>,
+) -> Result<bool, AskError> {
+    let mut messages = build_messages(chatlog, system_prompt, prompt, &backend.model, backend.context_tokens);
+    // the project context follows the system prompt and isn't saved, it is read again on every run
+    let mut position = usize::from(system_prompt.is_some());
+    if let Some(context) = &backend.project_context {
+        messages.insert(position, create_message("system".to_string(), context.clone()));
+        position += 1;
+    }
+    // then the stacked --append-system messages in order, like the context they aren't saved
+    for text in &args.append_system {
+        messages.insert(position, create_message("system".to_string(), text.clone()));
+        position += 1;
+    }
+    // images go with the prompt only, the history keeps a note of them
+    if !args.image.is_empty() {
+        let mut parts = vec![ContentPart::Text { text: prompt.to_string() }];
+        for path in &args.image {
+            let url = prompt::image_data_url(path)?;
+            parts.push(ContentPart::ImageUrl { image_url: ImageUrl { url } });
+        }
+        if let Some(message) = messages.last_mut() {
+            message.content = MessageContent::Parts(parts);
+        }
+    }
+    let dropped = fit_to_budget(&mut messages, position, &backend.model, backend.context_tokens);
+    if dropped > 0 {
+        eprintln!(
+            "Warning: dropped {} earlier turns to fit the {} token context budget",
+            dropped, backend.context_tokens
+        );
+    }
+    // the prompt and the room left for the answer, the API rejects requests that don't fit
+    if let Some(window) = context_window(&backend.model).filter(|_| !args.quiet) {
+        let tokens = messages_token_estimate(&messages, &backend.model) + backend.max_tokens.unwrap_or(0);
+        if tokens as f64 > window as f64 * CONTEXT_WARNING_RATIO {
+            eprintln!(
+                "Warning: the request is about {} tokens, {}% of the {} token context window of {}",
+                tokens,
+                tokens * 100 / window,
+                window,
+                backend.model
+            );
+        }
+    }
+    // run the tools the model asks for and send their results back until it answers
+    let mut tool_logs = vec![];
+    let mut rounds = 0;
+    let completion = loop {
+        let completion = match send_messages(backend, args, messages.clone()).await? {
+            Some(completion) => completion,
+            None => return Ok(false),
+        };
+        if completion.tool_calls.is_empty() {
+            break completion;
+        }
+        rounds += 1;
+        if rounds > MAX_TOOL_ROUNDS {
+            return Err(AskError::Response(format!(
+                "the model still called tools after {} rounds",
+                MAX_TOOL_ROUNDS
+            )));
+        }
+
+        let mut request = create_message("assistant".to_string(), completion.content.clone());
+        request.tool_calls = Some(completion.tool_calls.clone());
+        messages.push(request);
+        for call in &completion.tool_calls {
+            let name = call["function"]["name"].as_str().unwrap_or_default();
+            let arguments = call["function"]["arguments"].as_str().unwrap_or("{}");
+            if !args.quiet {
+                eprintln!("[tool] {}({})", name, arguments);
+            }
+            let result = tools::call(&backend.tools, name, arguments);
+            tool_logs.push(format!("{}({}) -> {}", name, arguments, result));
+            let mut response = create_message("tool".to_string(), result);
+            response.tool_call_id = call["id"].as_str().map(str::to_string);
+            messages.push(response);
+        }
+    };
+
+    // the changes since the last answer to the same question, or just the answer the first time
+    if args.compare {
+        match previous {
+            Some(previous) if previous == completion.content => {
+                print_answer(args, &completion.content);
+                eprintln!("The answer is the same as before");
+            }
+            Some(previous) => {
+                print!("{}", render::diff_lines(previous, &completion.content, render::should_use_color()))
+            }
+            None => print_answer(args, &completion.content),
+        }
+    }
+
+    if args.json {
+        let mut output = serde_json::json!({
+            "answer": completion.content,
+            "model": backend.model,
+            "prompt_tokens": completion.prompt_tokens,
+            "completion_tokens": completion.completion_tokens,
+            "reasoning_tokens": completion.reasoning_tokens,
+            "system_fingerprint": completion.system_fingerprint,
+            "service_tier": completion.service_tier,
+            "finish_reason": completion.finish_reason,
+            "cached": completion.cached,
+            "latency_ms": completion.latency.map(|latency| latency.as_millis() as u64),
+            "first_token_ms": completion.first_token.map(|first_token| first_token.as_millis() as u64),
+        });
+        if !completion.other_choices.is_empty() {
+            let choices = std::iter::once(&completion.content).chain(&completion.other_choices);
+            output["choices"] = serde_json::json!(choices.collect::<Vec<_>>());
+        }
+        if args.logprobs {
+            output["logprobs"] = serde_json::json!(completion.logprobs);
+        }
+        println!("{}", output);
+    } else if !args.quiet && (args.stats || env::var("ASK_SHOW_STATS").is_ok_and(|x| x == "1")) {
+        eprintln!(
+            "{}",
+            stats::format_usage(&backend.model, completion.prompt_tokens, completion.completion_tokens)
+        );
+        if let Some(reasoning_tokens) = completion.reasoning_tokens.filter(|&tokens| tokens > 0) {
+            eprintln!("{}", stats::format_reasoning(completion.completion_tokens, reasoning_tokens));
+        }
+        if let Some(fingerprint) = &completion.system_fingerprint {
+            eprintln!("[system fingerprint: {}]", fingerprint);
+        }
+        if let Some(tier) = &completion.service_tier {
+            eprintln!("[service tier: {}]", tier);
+        }
+        if completion.cached {
+            eprintln!("[cached answer, no tokens were spent]");
+        }
+        if let Some(rate_limit) = &completion.rate_limit {
+            eprintln!("{}", ratelimit::format(rate_limit));
+        }
+        if let Some(latency) = completion.latency {
+            eprintln!("{}", stats::format_latency(latency, completion.first_token));
+        }
+    }
+    if args.logprobs && !args.json {
+        if completion.logprobs.is_empty() {
+            eprintln!("Warning: the endpoint returned no logprobs");
+        } else {
+            print!("{}", stats::format_logprobs(&completion.logprobs));
+        }
+    }
+    if let Some(reason) = completion.finish_reason.as_deref() {
+        if !matches!(reason, "stop" | "end_turn" | "stop_sequence") {
+            eprintln!("Warning: the answer may be incomplete (finish reason: {})", reason);
+            if matches!(reason, "length" | "max_tokens") && !args.no_history {
+                eprintln!("Run ask --continue to get the rest");
+            }
+        }
+    }
+    if args.copy {
+        copy_to_clipboard(&completion.content);
+    }
+    // an audit trail of every request, separate from the history and never trimmed
+    if let Some(path) = env::var_os("ASK_AUDIT_LOG").filter(|x| !x.is_empty()) {
+        if let Err(e) = audit::record(Path::new(&path), &backend.model, prompt, &completion) {
+            eprintln!("Warning: could not write to the audit log: {}", e);
+        }
+    }
+
+    // save the new messages to the chatlog, remembering the system prompt if it changed
+    if let Some(system_prompt) = system_prompt {
+        if saved_system_prompt(chatlog) != Some(system_prompt) {
+            let tokens = count_tokens(system_prompt, &backend.model);
+            chatlog.push(create_log("system".to_string(), system_prompt.to_string(), tokens));
+        }
+    }
+    // with several choices the usage covers all of them, but only the first one is kept
+    let answer_tokens = if completion.other_choices.is_empty() {
+        completion.completion_tokens
+    } else {
+        if !args.no_history {
+            eprintln!("Only choice 1 is kept in the conversation history");
+        }
+        count_tokens(&completion.content, &backend.model)
+    };
+    let mut question = prompt.to_string();
+    for path in &args.image {
+        question.push_str(&format!("\n[image: {}]", path.display()));
+    }
+    // asking the same cached question again, e.g. from a hook, doesn't keep adding it to the history
+    if completion.cached && ends_with_exchange(chatlog, &question, &completion.content) {
+        return Ok(completion.interrupted);
+    }
+    chatlog.push(create_log("user".to_string(), question, completion.prompt_tokens));
+    for log in tool_logs {
+        let tokens = count_tokens(&log, &backend.model);
+        chatlog.push(create_log("tool".to_string(), log, tokens));
+    }
+    chatlog.push(create_log("assistant".to_string(), completion.content, answer_tokens));
+
+    Ok(completion.interrupted)
+}
+
+// keep asking until /exit or Ctrl-D, saving the chatlog after every answer
+async fn repl(
+    backend: &Backend,
+    args: &CliArgs,
+    chatlog_path: &Path,
+    chatlog: &mut Vec<Log>,
+    mut system_prompt: Option<String>,
+) -> Result<(), AskError> {
+    let mut reader = input::LineReader::new(ask_dir().join("repl_history"));
+    loop {
+        let Some(line) = reader.read_line("> ")? else {
+            return Ok(());
+        };
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line == "/exit" {
+            return Ok(());
+        }
+        if line == "/clear" {
+            chatlog.clear();
+            if !args.no_history {
+                clear_chatlog(chatlog_path)?;
+            }
+            println!("Conversation cleared");
+            continue;
+        }
+        if let Some(text) = line.strip_prefix("/system") {
+            match text.trim() {
+                "" => println!("{}", system_prompt.as_deref().unwrap_or("No system prompt set")),
+                text => {
+                    system_prompt = Some(text.to_string());
+                    println!("System prompt set");
+                }
+            }
+            continue;
+        }
+
+        // pick up turns another run saved in the meantime, and keep it from saving until this one is done
+        let _lock = if args.no_history {
+            None
+        } else {
+            let lock = lock_chatlog(chatlog_path)?;
+            *chatlog = load_chatlog(chatlog_path)?;
+            Some(lock)
+        };
+
+        // a failed request shouldn't end the conversation
+        if args.summarize_history && !args.dry_run && !args.raw {
+            if let Err(e) = summarize_history(backend, chatlog, line).await {
+                eprintln!("Error: {}", e);
+                continue;
+            }
+        }
+        let previous = previous_answer(chatlog, line).map(str::to_string);
+        let turn = take_turn(backend, args, chatlog, system_prompt.as_deref(), line, previous.as_deref());
+        let interrupted = match turn.await {
+            Ok(interrupted) => interrupted,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                continue;
+            }
+        };
+        if !args.no_history {
+            save_chatlog(chatlog_path, chatlog)?;
+            if args.session.is_some() {
+                session::save_model(chatlog_path, &backend.model)?;
+                ensure_title(backend, chatlog_path, chatlog).await;
+            }
+        }
+        if interrupted {
+            process::exit(130);
+        }
+    }
+}
+
+// load the first .env found in the working directory, ~/.ask or next to the binary.
+// none at all is fine, the variables may be set in the environment
+fn load_dotenv() {
+    let mut candidates = vec![PathBuf::from(".env")];
+    if let Some(home) = dirs::home_dir() {
+        candidates.push(home.join(".ask").join(".env"));
+    }
+    if let Ok(mut path) = current_exe() {
+        path.pop(); // This will remove the binary name from the path.
+        candidates.push(path.join(".env"));
+    }
+
+    if let Some(path) = candidates.iter().find(|path| path.is_file()) {
+        if let Err(e) = dotenv::from_path(path) {
+            eprintln!("Warning: could not load {}: {}", path.display(), e);
+        }
+    }
+}
+
+// plain `ask 0.1.0` for scripts, the details of the build for bug reports
+fn print_version(verbose: bool) {
+    println!("ask {}", env!("CARGO_PKG_VERSION"));
+    if verbose {
+        println!("commit: {}", env!("ASK_GIT_COMMIT"));
+        println!("built: {}", env!("ASK_BUILD_DATE"));
+        println!("rustc: {}", env!("ASK_RUSTC_VERSION"));
+        println!("target: {}", env!("ASK_TARGET"));
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    load_dotenv();
+
+    let argv: Vec<OsString> = env::args_os().collect();
+    let args = parse_args(&argv).unwrap_or_else(|e| {
+        // most likely a question that starts like a subcommand, e.g. ask export csv from postgres
+        let first = argv.get(1).and_then(|x| x.to_str()).unwrap_or_default();
+        let subcommand = cli_command().get_subcommands().any(|command| command.get_name() == first);
+        if e.use_stderr() && subcommand && first != "chat" {
+            e.print().ok();
+            eprintln!("\nTo ask a question that starts with \"{}\", use ask chat {} ... or quote it", first, first);
+            process::exit(2);
+        }
+        e.exit()
+    });
+    handle_ctrl_c();
+
+    // in JSON mode errors are part of the output too
+    if let Err(e) = run(&args).await {
+        if args.json {
+            println!("{}", serde_json::json!({ "error": e.to_string(), "exit_code": e.exit_code() }));
+        } else {
+            eprintln!("Error: {}", e);
+        }
+        process::exit(e.exit_code());
+    }
+}
+
+async fn run(args: &CliArgs) -> Result<(), AskError> {
+    if args.version {
+        print_version(args.verbose);
+        return Ok(());
+    }
+    if let Some(Command::Completions { shell }) = &args.command {
+        clap_complete::generate(*shell, &mut cli_command(), "ask", &mut io::stdout());
+        return Ok(());
+    }
+    if let Some(Command::Config { path }) = &args.command {
+        let ask_dir = ask_dir();
+        // loading checks the file, and writes the defaults on first run
+        config::load(&ask_dir)?;
+        let config_path = config::path(&ask_dir);
+        if *path {
+            println!("{}", config_path.display());
+        } else {
+            print!("{}", fs::read_to_string(&config_path)?);
+        }
+        return Ok(());
+    }
+
+    if !args.prompt.is_empty() {
+        let without_prompt = [
+            ("--prompt-file", args.prompt_file.is_some()),
+            ("--regenerate", args.regenerate),
+            ("--continue", args.continue_answer),
+            ("--branch", args.branch.is_some()),
+        ];
+        if let Some((flag, _)) = without_prompt.iter().find(|(_, set)| *set) {
+            return Err(AskError::Config(format!("{} can't be used with a prompt", flag)));
+        }
+    }
+
+    // get the prompt from the user, followed by anything piped into stdin.
+    // the REPL reads its prompts from stdin itself
+    let mut prompt = args.prompt.join(" ");
+    if args.edit {
+        prompt = prompt::edit_prompt(&prompt)?.trim().to_string();
+        if prompt.is_empty() {
+            return Err(AskError::Config("Aborting, the prompt is empty".to_string()));
+        }
+    }
+    let asks_about_files = prompt::has_file_refs(&prompt) || !args.context_glob.is_empty();
+    let mut prompt = prompt::expand_file_refs(&prompt)?;
+    // commands that never ask don't wait for stdin, it may be a pipe that stays open in a hook or CI.
+    // --clear, --undo and --title only ask when given a prompt on the command line
+    let housekeeping = args.list_personas
+        || args.list_sessions
+        || args.branch.is_some()
+        || args.clear_cache
+        || args.history.is_some()
+        || args.export.is_some()
+        || args.prune
+        || args.list_models
+        || ((args.clear || args.undo || args.title.is_some())
+            && prompt.is_empty()
+            && args.template.is_none()
+            && args.script.is_empty());
+    let mut piped = String::new();
+    if !args.repl
+        && !housekeeping
+        && !io::stdin().is_terminal()
+        && args.prompt_file.as_deref() != Some(Path::new("-"))
+    {
+        io::stdin().read_to_string(&mut piped)?;
+    }
+    // a prompt file is the whole prompt, taken as it is
+    if let Some(path) = &args.prompt_file {
+        if !piped.trim().is_empty() {
+            return Err(AskError::Config("The prompt can come from --prompt-file or stdin, not both".to_string()));
+        }
+        prompt = if path == Path::new("-") {
+            io::read_to_string(io::stdin())?
+        } else {
+            fs::read_to_string(path)
+                .map_err(|e| AskError::Config(format!("could not read {}: {}", path.display(), e)))?
+        };
+        prompt = prompt.trim_end().to_string();
+    } else if !piped.trim().is_empty() {
+        if !prompt.is_empty() {
+            prompt.push('\n');
+        }
+        prompt.push_str(piped.trim_end());
+    }
+    // a template comes first, the prompt after it adds to the instructions
+    if let Some(name) = &args.template {
+        let text = template::render_template(&ask_dir(), name, &args.var)?;
+        prompt = if prompt.is_empty() { text } else { format!("{}\n\n{}", text, prompt) };
+    }
+
+    // without a prompt, a scripted conversation ends with its last --user message
+    let mut script = args.script.clone();
+    if prompt.is_empty() && script.last().is_some_and(|(role, _)| role == "user") {
+        prompt = script.pop().map(|(_, text)| text).unwrap_or_default();
+    }
+    if prompt.is_empty() && !script.is_empty() {
+        return Err(AskError::Config("A scripted conversation needs a prompt or a final --user".to_string()));
+    }
+
+    // without a prompt in a terminal, start a conversation instead of a single question
+    let repl_mode = args.repl
+        || (prompt.is_empty()
+            && !args.clear
+            && !args.regenerate
+            && !args.continue_answer
+            && !args.undo
+            && args.prompt_file.is_none()
+            && args.count_tokens.is_none()
+            && args.title.is_none()
+            && args.script.is_empty()
+            && io::stdin().is_terminal());
+
+    let ask_dir = ask_dir();
+
+    if args.list_personas {
+        for name in persona::list(&ask_dir)? {
+            println!("{}", name);
+        }
+        return Ok(());
+    }
+
+    // the persona text is the prompt, or written in $EDITOR when there is none
+    // the whole file is the system prompt, @path in it stays as it is
+    let system_file = match &args.system_file {
+        Some(path) => Some(
+            fs::read_to_string(path)
+                .map_err(|e| AskError::Config(format!("could not read {}: {}", path.display(), e)))?,
+        ),
+        None => None,
+    };
+
+    if let Some(name) = &args.save_persona {
+        let text = if let Some(text) = &system_file {
+            text.clone()
+        } else if prompt.trim().is_empty() {
+            prompt::edit_prompt("")?
+        } else {
+            prompt.clone()
+        };
+        if text.trim().is_empty() {
+            return Err(AskError::Config("Aborting, the persona is empty".to_string()));
+        }
+        let path = persona::save(&ask_dir, name, &text)?;
+        println!("Saved the persona to {}", path.display());
+        return Ok(());
+    }
+
+    if args.list_sessions {
+        for name in list_sessions(&ask_dir)? {
+            // the meta file is <name>.meta.json whatever the format of the session
+            match session::load_meta(&session_path(&ask_dir, &name, LogFormat::Json)?).title {
+                Some(title) => println!("{:<20} {}", name, title),
+                None => println!("{}", name),
+            }
+        }
+        return Ok(());
+    }
+
+    let config = config::load(&ask_dir)?;
+    let log_format = config.log_format.unwrap_or_default();
+
+    // load the chatlog for the named session, or from --log-path, ASK_LOG_PATH or ~/.ask/ask_log.json
+    let chatlog_path = match &args.session {
+        Some(name) => session_path(&ask_dir, name, log_format)?,
+        None => args
+            .log_path
+            .clone()
+            .or_else(|| env::var_os("ASK_LOG_PATH").filter(|x| !x.is_empty()).map(PathBuf::from))
+            .unwrap_or_else(|| log_file(&ask_dir, "ask_log", log_format)),
+    };
+
+    // copy the conversation so far into a new session, the original stays as it is
+    if let Some(name) = &args.branch {
+        let branch_path = session_path(&ask_dir, name, log_format)?;
+        if branch_path.exists() {
+            return Err(AskError::Config(format!("A session named {} already exists", name)));
+        }
+        let chatlog = load_chatlog(&chatlog_path)?;
+        fs::create_dir_all(ask_dir.join("sessions"))?;
+        save_chatlog(&branch_path, &chatlog)?;
+        println!(
+            "Branched {} messages into session {}, continue it with ask --session {}",
+            chatlog.len(),
+            name,
+            name
+        );
+        return Ok(());
+    }
+
+    if args.clear_cache {
+        println!("Removed {} cached answers", cache::clear(&ask_dir)?);
+        return Ok(());
+    }
+
+    // show the recent conversation
+    if let Some(count) = args.history {
+        let chatlog = load_chatlog(&chatlog_path)?;
+        print!("{}", history::format_recent(&chatlog, count, render::should_use_color()));
+        return Ok(());
+    }
+
+    // write the conversation as markdown to a file, or stdout for -
+    if let Some(export_path) = &args.export {
+        let chatlog = load_chatlog(&chatlog_path)?;
+        let markdown = history::to_markdown(&chatlog);
+        if export_path == "-" {
+            print!("{}", markdown);
+        } else {
+            fs::write(export_path, markdown)?;
+            eprintln!("Exported {} messages to {}", chatlog.len(), export_path);
+        }
+        return Ok(());
+    }
+
+    // wipe the conversation history before doing anything else
+    if args.clear {
+        clear_chatlog(&chatlog_path)?;
+        println!("Conversation cleared");
+        if prompt.is_empty() && !repl_mode {
+            return Ok(());
+        }
+    }
+
+    // forget the last question and answer, e.g. when a bad answer would derail the conversation
+    if args.undo {
+        let _lock = lock_chatlog(&chatlog_path)?;
+        let mut chatlog = load_chatlog(&chatlog_path)?;
+        match pop_last_exchange(&mut chatlog) {
+            Ok((question, answer)) => {
+                save_chatlog(&chatlog_path, &chatlog)?;
+                println!("Removed the last exchange:\n> {}\n{}", question, answer);
+            }
+            Err(_) => println!("Nothing to undo, the conversation doesn't end with an answer"),
+        }
+        if prompt.is_empty() && !repl_mode {
+            return Ok(());
+        }
+    }
+
+    // name the conversation, on its own or before asking
+    if let Some(title) = &args.title {
+        let mut meta = session::load_meta(&chatlog_path);
+        meta.title = Some(title.clone());
+        session::save_meta(&chatlog_path, &meta)?;
+        if prompt.is_empty() && !repl_mode {
+            return Ok(());
+        }
+    }
+
+    // nothing to ask, don't waste a request on an empty message
+    if prompt.trim().is_empty()
+        && !repl_mode
+        && !args.regenerate
+        && !args.continue_answer
+        && !args.list_models
+        && !args.prune
+        && args.count_tokens.is_none()
+    {
+        eprintln!("{}", cli_command().render_usage());
+        return Err(AskError::Config("No prompt provided".to_string()));
+    }
+
+    // drop old entries by the limits in the config file, this happens on every run anyway
+    if args.prune {
+        if config.max_log_entries.is_none() && config.max_log_age_days.is_none() {
+            println!("Nothing to prune, set max_log_entries or max_log_age_days in ~/.ask/config.toml");
+            return Ok(());
+        }
+        let _lock = lock_chatlog(&chatlog_path)?;
+        let mut chatlog = load_chatlog(&chatlog_path)?;
+        let pruned = history::prune(&mut chatlog, config.max_log_entries, config.max_log_age_days);
+        save_chatlog(&chatlog_path, &chatlog)?;
+        println!("Pruned {} entries, {} left", pruned, chatlog.len());
+        return Ok(());
+    }
+
+    // CLI arguments take precedence over the config file
+    let temperature = args.temperature.or(config.temperature);
+    let max_tokens = args.max_tokens.or(config.max_tokens);
+    let presence_penalty = args.presence_penalty.or(config.presence_penalty);
+    let frequency_penalty = args.frequency_penalty.or(config.frequency_penalty);
+
+    check_range("--temperature", temperature, 0.0, 2.0)?;
+    check_range("--top-p", args.top_p, 0.0, 1.0)?;
+    check_range("--presence-penalty", presence_penalty, -2.0, 2.0)?;
+    check_range("--frequency-penalty", frequency_penalty, -2.0, 2.0)?;
+    if args.stop.len() > MAX_STOP_SEQUENCES {
+        return Err(AskError::Config(format!(
+            "at most {} --stop sequences are allowed, got {}",
+            MAX_STOP_SEQUENCES,
+            args.stop.len()
+        )));
+    }
+    if max_tokens.is_some_and(|n| n <= 0) {
+        return Err(AskError::Config("--max-tokens must be a positive number".to_string()));
+    }
+
+    // Get the model from the CLI argument, environment variable, the session or config file, the default depends
+    // on the provider. a session keeps the model it was last continued with, so it doesn't switch by accident
+    let model = args
+        .model
+        .clone()
+        .or_else(|| env::var("CHATGPT_CLI_MODEL").ok())
+        .or_else(|| args.session.as_ref().and_then(|_| session::load_meta(&chatlog_path).model))
+        .or_else(|| config.model.clone())
+        // aliases from the config file, anything else is a model name
+        .map(|model| config.aliases.get(&model).cloned().unwrap_or(model));
+
+    // use the provider from the CLI argument, otherwise guess it from the model name and the environment
+    let provider = args.provider.unwrap_or_else(|| match &model {
+        Some(model) if model.starts_with("claude") => Provider::Anthropic,
+        _ if env::var_os("AZURE_OPENAI_ENDPOINT").is_some_and(|x| !x.is_empty()) => Provider::Azure,
+        _ => Provider::Openai,
+    });
+
+    // on Azure the model is the name of a deployment
+    let model = match model {
+        Some(model) => model,
+        None => match provider {
+            Provider::Openai => "gpt-3.5-turbo".to_string(),
+            Provider::Anthropic => anthropic::DEFAULT_MODEL.to_string(),
+            Provider::Azure => env::var("AZURE_OPENAI_DEPLOYMENT").map_err(|_| {
+                AskError::Config("Set AZURE_OPENAI_DEPLOYMENT or pass --model with the deployment name".to_string())
+            })?,
+        },
+    };
+
+    // the files of --context-glob go after the prompt, each costing what its block does
+    if !args.context_glob.is_empty() {
+        let mut total = 0;
+        for (path, block) in prompt::glob_files(&args.context_glob)? {
+            let tokens = count_tokens(&block, &model);
+            if !args.quiet {
+                eprintln!("Attached {} ({} tokens)", path.display(), tokens);
+            }
+            total += tokens;
+            prompt.push_str("\n\n");
+            prompt.push_str(&block);
+        }
+        if let Some(window) = context_window(&model).filter(|&window| total > window) {
+            return Err(AskError::Config(format!(
+                "the attached files are {} tokens, more than the {} token context window of {}",
+                total, window, model
+            )));
+        }
+        if !args.quiet {
+            eprintln!("Attached {} tokens of files", total);
+        }
+    }
+
+    // count locally with the tokenizer of the model and stop there, without a key or a request
+    if let Some(path) = &args.count_tokens {
+        let text = match path {
+            Some(path) => fs::read_to_string(path)
+                .map_err(|e| AskError::Config(format!("could not read {}: {}", path.display(), e)))?,
+            None => prompt,
+        };
+        println!("{}", count_tokens(&text, &model));
+        return Ok(());
+    }
+    if !args.image.is_empty() {
+        if provider == Provider::Anthropic {
+            return Err(AskError::Config("--image is only supported with OpenAI-compatible APIs".to_string()));
+        }
+        if !supports_images(&model) {
+            return Err(AskError::Config(format!("{} doesn't accept images, try gpt-4o", model)));
+        }
+    }
+    if provider == Provider::Anthropic && args.choices.is_some_and(|n| n > 1) {
+        return Err(AskError::Config("--choices is not supported by Anthropic".to_string()));
+    }
+    if provider == Provider::Anthropic && args.response_format == Some(AnswerFormat::Json) {
+        return Err(AskError::Config("--response-format json is not supported by Anthropic".to_string()));
+    }
+    if provider == Provider::Anthropic && args.logprobs {
+        return Err(AskError::Config("--logprobs is not supported by Anthropic".to_string()));
+    }
+    if provider == Provider::Anthropic && args.service_tier.is_some() {
+        return Err(AskError::Config("--service-tier is not supported by Anthropic".to_string()));
+    }
+    if provider == Provider::Anthropic && args.tools {
+        return Err(AskError::Config("--tools is not supported by Anthropic".to_string()));
+    }
+
+    // get the API key and endpoint for the provider from environment variables
+    let (key, api_base_key, default_api_base) = match provider {
+        Provider::Openai => (
+            "OPENAI_API_KEY",
+            "OPENAI_API_BASE",
+            "https://api.openai.com/v1/chat/completions",
+        ),
+        Provider::Anthropic => ("ANTHROPIC_API_KEY", "ANTHROPIC_API_BASE", anthropic::DEFAULT_API_BASE),
+        Provider::Azure => ("AZURE_OPENAI_KEY", "AZURE_OPENAI_ENDPOINT", ""),
+    };
+    let api_base = if provider == Provider::Azure {
+        let endpoint = env::var(api_base_key)
+            .ok()
+            .filter(|x| !x.is_empty())
+            .ok_or_else(|| AskError::Config(format!("{} not set", api_base_key)))?;
+        azure_url(&endpoint, &model)?
+    } else {
+        let api_base = env::var(api_base_key)
+            .ok()
+            .or_else(|| config.api_base.clone().filter(|_| provider == Provider::Openai))
+            .unwrap_or_else(|| match provider {
+                Provider::Openai if args.local => OLLAMA_API_BASE.to_string(),
+                _ => default_api_base.to_string(),
+            });
+        normalize_api_base(api_base_key, &api_base, default_api_base)?
+    };
+
+    // a canned answer for testing scripts offline, without a key or network
+    let mock_response = args.mock.clone().or_else(|| env::var("ASK_MOCK_RESPONSE").ok());
+
+    // local servers like Ollama and LM Studio don't need a key, and neither does a mock answer
+    let api_key = env::var(key).ok().filter(|key| !key.trim().is_empty());
+    if api_key.is_none() && !args.local && !is_local_url(&api_base) && mock_response.is_none() {
+        return Err(AskError::Auth(format!("{} not set", key)));
+    }
+    let expected_prefix = match provider {
+        Provider::Openai if api_base == default_api_base => Some("sk-"),
+        Provider::Anthropic if api_base == default_api_base => Some("sk-ant-"),
+        _ => None,
+    };
+    let api_key = api_key.map(|api_key| validate_api_key(key, &api_key, expected_prefix));
+
+    if let (Some(max_tokens), Some(window)) = (max_tokens, context_window(&model)) {
+        if max_tokens > window / 2 {
+            eprintln!(
+                "Warning: --max-tokens {} is more than half of the {} token context window of {}",
+                max_tokens, window, model
+            );
+        }
+    }
+
+    // how much history to send: by default half the model's window, leaving the rest for the answer
+    let window = context_window(&model);
+    let mut context_tokens = args
+        .context_tokens
+        .or_else(|| env::var("ASK_CONTEXT_TOKENS").ok().and_then(|x| x.parse().ok()))
+        .or(config.context_tokens)
+        .unwrap_or_else(|| window.map(|window| window / 2).unwrap_or(DEFAULT_CONTEXT_TOKENS));
+    if let Some(window) = window {
+        if context_tokens > window {
+            eprintln!(
+                "Warning: a context budget of {} tokens is more than {} supports, using {}",
+                context_tokens, model, window
+            );
+            context_tokens = window;
+        }
+    }
+
+    let invalid_key = |_| AskError::Config(format!("{} contains invalid characters", key));
+    let mut headers = HeaderMap::new();
+    match (provider, api_key) {
+        (Provider::Openai, Some(api_key)) => {
+            headers.insert(AUTHORIZATION, format!("Bearer {}", api_key).parse().map_err(invalid_key)?);
+        }
+        (Provider::Anthropic, Some(api_key)) => {
+            headers.insert("x-api-key", api_key.parse().map_err(invalid_key)?);
+        }
+        (Provider::Azure, Some(api_key)) => {
+            headers.insert("api-key", api_key.parse().map_err(invalid_key)?);
+        }
+        (_, None) => {}
+    };
+    if provider == Provider::Anthropic {
+        headers.insert("anthropic-version", HeaderValue::from_static(anthropic::API_VERSION));
+    }
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    // extra headers for gateways, they replace the defaults of the same name
+    for header in &args.header {
+        let (name, value) = parse_header(header)?;
+        headers.insert(name, value);
+    }
+    let timeout_secs = args
+        .timeout
+        .or_else(|| env::var("CHATGPT_CLI_REQUEST_TIMEOUT_SECS").ok().and_then(|x| x.parse().ok()))
+        .or(config.timeout_secs)
+        .unwrap_or(DEFAULT_TIMEOUT_SECS); // default value of 120 seconds
+
+    // project instructions from a .ask.md in the working directory or above
+    let project_context = match prompt::find_context_file() {
+        Some(path) if !args.no_context => Some(fs::read_to_string(&path)?),
+        _ => None,
+    };
+
+    // only deterministic requests are cached, unless the config says otherwise. questions about files are
+    // always cached: the contents are part of the prompt, so the answer is reused only while they are unchanged,
+    // whatever the conversation was before
+    let use_cache = (args.cache || env::var("ASK_CACHE").is_ok_and(|x| x == "1") || asks_about_files)
+        && !args.no_cache
+        && !args.raw;
+    let deterministic =
+        temperature == Some(0.0) || config.cache_any_temperature.unwrap_or(false) || asks_about_files;
+    if args.cache && !deterministic {
+        eprintln!("Warning: not caching, only answers with --temperature 0 are cached (see cache_any_temperature)");
+    }
+    let cache =
+        (use_cache && deterministic).then(|| ResponseCache::new(&ask_dir, config.cache_ttl_hours, asks_about_files));
+
+    let backend = Backend {
+        client: build_client(args.proxy.as_deref())?,
+        provider,
+        model,
+        api_base,
+        headers,
+        key_name: key,
+        timeout_secs,
+        temperature,
+        presence_penalty,
+        frequency_penalty,
+        max_tokens,
+        context_tokens,
+        project_context,
+        spinner_message: config.spinner_message.clone().unwrap_or_else(|| DEFAULT_SPINNER_MESSAGE.to_string()),
+        cache,
+        mock_response,
+        roles: config.roles.clone(),
+        role_prefix: config.role_prefix.clone(),
+        user_id: args.user_id.clone().or_else(|| config.user_id.clone()),
+        tools: config.tools,
+        debug: args.verbose || env::var("ASK_DEBUG").is_ok_and(|x| x == "1"),
+    };
+    if backend.debug {
+        debug_log("model", &backend.model);
+    }
+
+    // reasoning models reject the sampling settings, say once which ones every request leaves out
+    if provider != Provider::Anthropic {
+        for name in adapt_to_model(&mut build_request(&RequestSettings::new(&backend, args), vec![])) {
+            eprintln!("Warning: {} doesn't support {}, leaving it out", backend.model, name);
+        }
+    }
+
+    if args.list_models {
+        if provider == Provider::Azure {
+            let message = "--list-models is not supported on Azure, deployments are listed in the Azure portal";
+            return Err(AskError::Config(message.to_string()));
+        }
+        for model in models::list_models(&backend, &ask_dir).await? {
+            if !args.chat_only || models::is_chat_model(&model) {
+                println!("{}", model);
+            }
+        }
+        return Ok(());
+    }
+
+    // the REPL locks the chatlog for each turn instead, a conversation can take a while.
+    // a dry run only reads, so it needs no lock
+    let _lock = if args.no_history || repl_mode || args.dry_run {
+        None
+    } else {
+        Some(lock_chatlog(&chatlog_path)?)
+    };
+
+    // one-off questions neither see nor touch the chatlog, and loading would create a missing one
+    let mut chatlog = if args.no_history || (args.dry_run && !chatlog_path.exists()) {
+        vec![]
+    } else {
+        load_chatlog(&chatlog_path)?
+    };
+    // dry runs and raw responses see the pruned and recounted chatlog without saving it
+    let pruned = history::prune(&mut chatlog, config.max_log_entries, config.max_log_age_days);
+    let recounted = recount_missing_tokens(&mut chatlog, &backend.model);
+    if (pruned > 0 || recounted > 0) && !args.dry_run && !args.raw {
+        save_chatlog(&chatlog_path, &chatlog)?;
+    }
+
+    // the system prompt from the CLI argument or file, a persona, environment variable, the last one used in this
+    // chatlog, or the config file
+    let persona = args.persona.as_deref().map(|name| persona::load(&ask_dir, name)).transpose()?;
+    let system_prompt = args
+        .system
+        .clone()
+        .or(system_file)
+        .or(persona)
+        .or_else(|| env::var("ASK_SYSTEM_PROMPT").ok())
+        .or_else(|| saved_system_prompt(&chatlog).map(str::to_string))
+        .or(config.system_prompt);
+
+    if repl_mode {
+        return repl(&backend, args, &chatlog_path, &mut chatlog, system_prompt).await;
+    }
+
+    // regenerating asks the last question again in place of the last answer
+    let (prompt, replaced) = if args.regenerate {
+        let (question, answer) = pop_last_exchange(&mut chatlog)?;
+        (question, Some(answer))
+    } else if args.continue_answer {
+        match chatlog.last() {
+            Some(log) if log.role == "assistant" => {
+                if log.tokens > backend.context_tokens {
+                    eprintln!(
+                        "Warning: the last answer is more than the {} token context budget, raise context_tokens \
+                         so the model sees it",
+                        backend.context_tokens
+                    );
+                }
+            }
+            _ => return Err(AskError::Config("The last message is not an answer, nothing to continue".to_string())),
+        }
+        (CONTINUE_PROMPT.to_string(), None)
+    } else {
+        (prompt, None)
+    };
+
+    // scripted turns become part of the conversation, as if they had been asked before
+    for (role, text) in script {
+        let tokens = count_tokens(&text, &backend.model);
+        chatlog.push(create_log(role, text, tokens));
+    }
+
+    if args.summarize_history && !args.dry_run && !args.raw {
+        summarize_history(&backend, &mut chatlog, &prompt).await?;
+    }
+
+    let asked_len = chatlog.len();
+    // --compare shows the changes from the replaced answer, or the last answer to the same question
+    let previous = replaced.or_else(|| previous_answer(&chatlog, &prompt).map(str::to_string));
+    let interrupted =
+        take_turn(&backend, args, &mut chatlog, system_prompt.as_deref(), &prompt, previous.as_deref()).await?;
+
+    if args.continue_answer {
+        merge_continuation(&mut chatlog, asked_len);
+    }
+
+    // write the chatlog to disk, unless a failed regenerate would lose the old answer
+    if !args.no_history && !args.dry_run && !args.raw && (!args.regenerate || chatlog.len() > asked_len) {
+        save_chatlog(&chatlog_path, &chatlog)?;
+        if args.session.is_some() {
+            session::save_model(&chatlog_path, &backend.model)?;
+            ensure_title(&backend, &chatlog_path, &chatlog).await;
+        }
+    }
+    if interrupted {
+        process::exit(130);
+    }
+
+    Ok(())
+}
+
+// the version flag is our own, so --version --verbose can show the build
+#[derive(Parser, Debug)]
+// no help subcommand, ask help me write a poem is a question
+#[clap(version = env!("CARGO_PKG_VERSION"), disable_version_flag = true, disable_help_subcommand = true)]
+struct CliArgs {
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Print the version, with --verbose also the commit, build date, compiler and target
+    #[clap(short = 'V', long)]
+    version: bool,
+
     /// The prompt to send to ChatGPT
     #[clap(name = "prompt")]
     prompt: Vec<String>,
 
-    /// The ChatGPT model to use (default: gpt-3.5-turbo)
+    /// The ChatGPT model to use, or an alias from the config file (default: gpt-3.5-turbo)
     #[clap(short, long)]
     model: Option<String>,
+
+    /// The API to send the prompt to (default: anthropic for claude-* models, otherwise openai)
+    #[clap(long, value_enum)]
+    provider: Option<Provider>,
+
+    /// Attach an image to the prompt for models with vision (repeatable)
+    #[clap(long, value_name = "PATH", conflicts_with = "repl")]
+    image: Vec<PathBuf>,
+
+    /// Read the whole prompt from a file, - for stdin
+    #[clap(short = 'f', long, value_name = "PATH", conflicts_with_all = ["edit", "repl"])]
+    prompt_file: Option<PathBuf>,
+
+    /// Write the prompt in $EDITOR
+    #[clap(long)]
+    edit: bool,
+
+    /// Sampling temperature between 0.0 and 2.0
+    #[clap(long)]
+    temperature: Option<f32>,
+
+    /// Nucleus sampling probability mass between 0.0 and 1.0
+    #[clap(long)]
+    top_p: Option<f32>,
+
+    /// Penalize tokens that already appeared, between -2.0 and 2.0 (ignored by providers without it)
+    #[clap(long, allow_negative_numbers = true)]
+    presence_penalty: Option<f32>,
+
+    /// Penalize tokens by how often they appeared, between -2.0 and 2.0 (ignored by providers without it)
+    #[clap(long, allow_negative_numbers = true)]
+    frequency_penalty: Option<f32>,
+
+    /// Add a header to every request, e.g. "X-Title: ask" (repeatable, replaces a default of the same name)
+    #[clap(long, value_name = "NAME: VALUE")]
+    header: Vec<String>,
+
+    /// Send requests through this HTTP or SOCKS5 proxy (default: $ALL_PROXY or $HTTPS_PROXY)
+    #[clap(long, value_name = "URL")]
+    proxy: Option<String>,
+
+    /// Seconds to wait for an answer, 0 for no limit (default: $CHATGPT_CLI_REQUEST_TIMEOUT_SECS or 120)
+    #[clap(long, value_name = "SECS")]
+    timeout: Option<u64>,
+
+    /// Stop generating at this sequence (repeatable, more than 4 is an error)
+    #[clap(long, value_name = "SEQ")]
+    stop: Vec<String>,
+
+    /// Ask for N alternative answers, only the first is kept in the history (OpenAI only)
+    #[clap(long, value_name = "N", conflicts_with = "stream", value_parser = clap::value_parser!(u32).range(1..))]
+    choices: Option<u32>,
+
+    /// Make the model answer with a JSON object, checked before it is printed (OpenAI only)
+    #[clap(long, value_name = "FORMAT", value_enum)]
+    response_format: Option<AnswerFormat>,
+
+    /// Show the log probability of every token under the answer (OpenAI only)
+    #[clap(long)]
+    logprobs: bool,
+
+    /// With --logprobs, also show the N most likely alternatives for every token
+    #[clap(long, value_name = "N", requires = "logprobs", value_parser = clap::value_parser!(u32).range(0..=20))]
+    top_logprobs: Option<u32>,
+
+    /// Sample deterministically (best effort) with this seed, e.g. with --temperature 0
+    #[clap(long, value_name = "N")]
+    seed: Option<i64>,
+
+    /// The maximum number of tokens to generate for the answer
+    #[clap(long, value_name = "N")]
+    max_tokens: Option<i64>,
+
+    /// How many tokens of conversation history to send (default: half the model's context window)
+    #[clap(long, value_name = "N")]
+    context_tokens: Option<i64>,
+
+    /// A system prompt to steer the model (default: $ASK_SYSTEM_PROMPT or the last one used)
+    #[clap(long)]
+    system: Option<String>,
+
+    /// Read the system prompt from a file, as it is (with --save-persona, save the file as the persona)
+    #[clap(long, value_name = "PATH", conflicts_with = "system")]
+    system_file: Option<PathBuf>,
+
+    /// Add a system message after the system prompt and the project context, can be repeated
+    #[clap(long, value_name = "TEXT")]
+    append_system: Vec<String>,
+
+    /// Use a saved system prompt from ~/.ask/personas/<NAME>.txt
+    #[clap(long, value_name = "NAME", conflicts_with = "system")]
+    persona: Option<String>,
+
+    /// Save the prompt (or text written in $EDITOR) as a persona
+    #[clap(long, value_name = "NAME", conflicts_with_all = ["persona", "repl"])]
+    save_persona: Option<String>,
+
+    /// Start the prompt from ~/.ask/templates/<NAME>.txt, filling in its {{placeholders}} with --var
+    #[clap(long, value_name = "NAME", conflicts_with = "repl")]
+    template: Option<String>,
+
+    /// A template variable, a value of @path is the contents of the file
+    #[clap(long, value_name = "NAME=VALUE", requires = "template")]
+    var: Vec<String>,
+
+    /// List the saved personas
+    #[clap(long)]
+    list_personas: bool,
+
+    /// Stream the answer as it is generated
+    #[clap(long)]
+    stream: bool,
+
+    /// Stream the answer to stderr and print it to stdout once complete (default when only stdout is redirected)
+    #[clap(long, requires = "stream")]
+    stream_stderr: bool,
+
+    /// Render markdown in the answer when printing to a terminal (also ASK_RENDER=1, ignored with --stream)
+    #[clap(long)]
+    render: bool,
+
+    /// Wrap long lines to the terminal width when printing to a terminal (also ASK_WRAP=1, ignored with --stream)
+    #[clap(long)]
+    wrap: bool,
+
+    /// Don't show the spinner while waiting for the answer
+    #[clap(long)]
+    no_spinner: bool,
+
+    /// Show a diff against the last answer to the same question (or the replaced one with --regenerate)
+    #[clap(long, conflicts_with_all = ["stream", "json", "quiet", "no_output", "choices", "no_history"])]
+    compare: bool,
+
+    /// Let the model call get_current_time and the tools from the config file before it answers
+    #[clap(long, conflicts_with_all = ["stream", "choices"])]
+    tools: bool,
+
+    /// How OpenAI processes the request, flex is cheaper for batch work that can wait
+    #[clap(long, value_enum, value_name = "TIER")]
+    service_tier: Option<ServiceTier>,
+
+    /// Identify the end user to the provider, e.g. when a team shares an API key
+    #[clap(long, value_name = "ID")]
+    user_id: Option<String>,
+
+    /// Answer with this text instead of calling the API, for testing scripts offline (also ASK_MOCK_RESPONSE)
+    #[clap(long, value_name = "TEXT")]
+    mock: Option<String>,
+
+    /// Print how many tokens the prompt (or --count-tokens=FILE) is for the model and exit, without calling the API
+    #[clap(long, value_name = "FILE", num_args = 0..=1, require_equals = true, conflicts_with = "repl")]
+    count_tokens: Option<Option<PathBuf>>,
+
+    /// Attach the files matching a glob like "src/**/*.rs" after the prompt, minus .gitignore'd ones (repeatable)
+    #[clap(long, value_name = "PATTERN")]
+    context_glob: Vec<String>,
+
+    /// Print the request that would be sent and exit, without calling the API or saving anything
+    #[clap(long, conflicts_with = "repl")]
+    dry_run: bool,
+
+    /// Print the full JSON response as it came from the API, without saving anything
+    #[clap(long, conflicts_with_all = ["stream", "json", "repl", "dry_run"])]
+    raw: bool,
+
+    /// Print the request and the raw response to stderr
+    #[clap(short, long)]
+    verbose: bool,
+
+    /// Use a local OpenAI-compatible server, no API key needed (defaults to Ollama)
+    #[clap(long)]
+    local: bool,
+
+    /// Don't include the .ask.md project context file
+    #[clap(long)]
+    no_context: bool,
+
+    /// Add a user message to the conversation, repeat with --assistant to script several turns
+    #[clap(long, value_name = "TEXT", conflicts_with_all = ["repl", "regenerate"])]
+    user: Vec<String>,
+
+    /// Add an assistant message to the conversation, in order with --user
+    #[clap(long, value_name = "TEXT", conflicts_with_all = ["repl", "regenerate"])]
+    assistant: Vec<String>,
+
+    // the --user and --assistant messages in command line order, filled in after parsing
+    #[clap(skip)]
+    script: Vec<(String, String)>,
+
+    /// Summarize the oldest turns when the history outgrows the context budget, instead of dropping them
+    #[clap(long, conflicts_with = "no_history")]
+    summarize_history: bool,
+
+    /// Replace the last answer with a new one for the same question
+    #[clap(long, conflicts_with_all = ["no_history", "repl"])]
+    regenerate: bool,
+
+    /// Ask for the rest of a cut off answer and add it to the last answer in the history
+    #[clap(long = "continue", conflicts_with_all = ["no_history", "repl", "regenerate", "undo"])]
+    continue_answer: bool,
+
+    /// Clear the conversation history before asking (or on its own)
+    #[clap(long)]
+    clear: bool,
+
+    /// Remove the last question and answer from the history before asking (or on its own)
+    #[clap(long, conflicts_with = "regenerate")]
+    undo: bool,
+
+    /// Print the answer and token usage as a single JSON object
+    #[clap(long)]
+    json: bool,
+
+    /// Show token usage and the estimated cost after the answer (also ASK_SHOW_STATS=1)
+    #[clap(long)]
+    stats: bool,
+
+    /// Copy the answer to the clipboard
+    #[clap(long)]
+    copy: bool,
+
+    /// Print only the plain answer, without the spinner, stats or styling (warnings still go to stderr)
+    #[clap(short, long)]
+    quiet: bool,
+
+    /// Don't print the answer, e.g. when it's only wanted on the clipboard
+    #[clap(long)]
+    no_output: bool,
+
+    /// Ask a one-off question without reading or saving the conversation history
+    #[clap(long, alias = "ephemeral")]
+    no_history: bool,
+
+    /// Keep chatting in an interactive loop (the default without a prompt)
+    #[clap(long)]
+    repl: bool,
+
+    /// Use a separate named conversation stored in ~/.ask/sessions/<NAME>.json
+    #[clap(long, value_name = "NAME")]
+    session: Option<String>,
+
+    /// Copy the conversation so far into a new session NAME to continue it differently
+    #[clap(long, value_name = "NAME", conflicts_with_all = ["repl", "no_history"])]
+    branch: Option<String>,
+
+    /// Keep the conversation history in this file instead of ~/.ask/ask_log.json (also ASK_LOG_PATH)
+    #[clap(long, value_name = "PATH", conflicts_with = "session")]
+    log_path: Option<PathBuf>,
+
+    /// Show the conversation so far, or only the last N entries
+    #[clap(long, value_name = "N", num_args = 0..=1)]
+    history: Option<Option<usize>>,
+
+    /// Export the conversation as a Markdown transcript to PATH, or - for stdout
+    #[clap(long, value_name = "PATH")]
+    export: Option<String>,
+
+    /// Drop old entries from the conversation history by max_log_entries and max_log_age_days in the config file
+    #[clap(long, conflicts_with = "no_history")]
+    prune: bool,
+
+    /// Answer repeated identical requests from ~/.ask/cache, by default only with --temperature 0 (also ASK_CACHE=1)
+    #[clap(long)]
+    cache: bool,
+
+    /// Always ask the API, even for questions about @files and --context-glob which are cached otherwise
+    #[clap(long, conflicts_with = "cache")]
+    no_cache: bool,
+
+    /// Remove all cached answers
+    #[clap(long)]
+    clear_cache: bool,
+
+    /// Give the conversation a title, shown by --list-sessions (sessions get one from their first question otherwise)
+    #[clap(long, conflicts_with = "no_history")]
+    title: Option<String>,
+
+    /// List the saved sessions
+    #[clap(long)]
+    list_sessions: bool,
+
+    /// List the models available from the provider
+    #[clap(long)]
+    list_models: bool,
+
+    /// Only list models that work with chat completions
+    #[clap(long, requires = "list_models")]
+    chat_only: bool,
+}
+
+impl CliArgs {
+    // the subcommands set the flags they stand for, ask history 10 is ask --history 10
+    fn apply_command(&mut self) {
+        match &self.command {
+            Some(Command::Chat { prompt }) => self.prompt.extend(prompt.iter().cloned()),
+            Some(Command::History { n }) => self.history = Some(*n),
+            Some(Command::Clear) => self.clear = true,
+            Some(Command::Sessions) => self.list_sessions = true,
+            Some(Command::Export { path }) => self.export = Some(path.clone()),
+            Some(Command::Models { chat_only }) => {
+                self.list_models = true;
+                self.chat_only |= chat_only;
+            }
+            Some(Command::Completions { .. } | Command::Config { .. }) | None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_lines_keeps_a_character_split_across_reads() {
+        let event = "data: {\"choices\":[{\"delta\":{\"content\":\"😀\"}}]}\n".as_bytes();
+        // cut inside the four bytes of the emoji
+        let split = event.iter().position(|&b| b == 0xF0).unwrap() + 2;
+        let mut pending = vec![];
+
+        assert!(take_lines(&mut pending, &event[..split]).is_empty());
+        let lines = take_lines(&mut pending, &event[split..]);
+        assert_eq!(lines.len(), 1);
+        assert!(pending.is_empty());
+
+        let data = lines[0].trim().strip_prefix("data:").unwrap().trim();
+        let chunk: serde_json::Value = serde_json::from_str(data).unwrap();
+        assert_eq!(delta_content(&chunk), Some("😀"));
+    }
+
+    #[test]
+    fn take_lines_splits_several_events_in_one_read() {
+        let mut pending = vec![];
+        let lines = take_lines(&mut pending, "data: a\n\ndata: b\ndata: c".as_bytes());
+        assert_eq!(lines, ["data: a\n", "\n", "data: b\n"]);
+        assert_eq!(take_lines(&mut pending, "\n".as_bytes()), ["data: c\n"]);
+    }
+
+    #[test]
+    fn fit_to_budget_drops_tool_results_with_their_turn() {
+        let big = "word ".repeat(500);
+        let mut messages = vec![
+            create_message("system".to_string(), "Be brief.".to_string()),
+            create_message("user".to_string(), "what's in the log?".to_string()),
+            create_message("system".to_string(), format!("read_log({{}}) -> {}", big)),
+            create_message("assistant".to_string(), "nothing much".to_string()),
+            create_message("user".to_string(), "q2".to_string()),
+            create_message("assistant".to_string(), "a2".to_string()),
+            create_message("user".to_string(), "and now?".to_string()),
+        ];
+        assert_eq!(fit_to_budget(&mut messages, 1, "gpt-4o", 100), 1);
+        assert_eq!(
+            roles_and_texts(&messages),
+            [
+                ("system".to_string(), "Be brief.".to_string()),
+                ("user".to_string(), "q2".to_string()),
+                ("assistant".to_string(), "a2".to_string()),
+                ("user".to_string(), "and now?".to_string()),
+            ]
+        );
+        // the pinned messages and the prompt stay even when they don't fit
+        assert_eq!(fit_to_budget(&mut messages, 1, "gpt-4o", 1), 1);
+        assert_eq!(messages.len(), 2);
+    }
+
+    fn parse(line: &str) -> Result<CliArgs, clap::Error> {
+        let argv: Vec<OsString> = line.split_whitespace().map(OsString::from).collect();
+        parse_args(&argv)
+    }
+
+    #[test]
+    fn cli_command_is_consistent() {
+        cli_command().debug_assert();
+    }
+
+    #[test]
+    fn a_bare_prompt_is_a_question() {
+        let args = parse("ask -m gpt-4o how are you").unwrap();
+        assert!(args.command.is_none());
+        assert_eq!(args.prompt, ["how", "are", "you"]);
+        assert_eq!(args.model.as_deref(), Some("gpt-4o"));
+    }
+
+    #[test]
+    fn chat_takes_the_prompt_and_options() {
+        let args = parse("ask chat -m gpt-4o history of rome --no-history").unwrap();
+        assert_eq!(args.prompt, ["history", "of", "rome"]);
+        assert_eq!(args.model.as_deref(), Some("gpt-4o"));
+        assert!(args.no_history);
+        assert_eq!(args.history, None);
+    }
+
+    #[test]
+    fn subcommands_set_their_flags() {
+        let args = parse("ask history 10 --session work").unwrap();
+        assert_eq!(args.history, Some(Some(10)));
+        assert_eq!(args.session.as_deref(), Some("work"));
+        assert_eq!(parse("ask history").unwrap().history, Some(None));
+        assert!(parse("ask clear").unwrap().clear);
+        assert!(parse("ask sessions").unwrap().list_sessions);
+        assert_eq!(parse("ask export - --session work").unwrap().export.as_deref(), Some("-"));
+        let args = parse("ask models --chat-only").unwrap();
+        assert!(args.list_models && args.chat_only);
+        assert!(args.prompt.is_empty());
+    }
+
+    #[test]
+    fn count_tokens_takes_a_file_only_after_equals() {
+        let args = parse("ask --count-tokens how long is this").unwrap();
+        assert_eq!(args.count_tokens, Some(None));
+        assert_eq!(args.prompt, ["how", "long", "is", "this"]);
+        let args = parse("ask --count-tokens=notes.txt").unwrap();
+        assert_eq!(args.count_tokens, Some(Some(PathBuf::from("notes.txt"))));
+    }
+
+    #[test]
+    fn help_starts_a_question() {
+        let args = parse("ask help me write").unwrap();
+        assert!(args.command.is_none());
+        assert_eq!(args.prompt, ["help", "me", "write"]);
+    }
+
+    #[test]
+    fn a_prompt_that_fits_a_subcommand_runs_it() {
+        // as the README says, ask export csv writes the transcript to csv
+        let args = parse("ask export csv").unwrap();
+        assert_eq!(args.export.as_deref(), Some("csv"));
+        assert!(args.prompt.is_empty());
+    }
+
+    #[test]
+    fn subcommands_reject_extra_words() {
+        for line in [
+            "ask export csv from postgres",
+            "ask clear the cache in redis",
+            "ask config file for nginx",
+            "ask history of rome",
+            "ask sessions in redis",
+            "ask models for coding",
+        ] {
+            assert!(parse(line).is_err(), "{} was accepted", line);
+        }
+    }
+
+    fn log(role: &str, content: &str, tokens: i64) -> Log {
+        create_log(role.to_string(), content.to_string(), tokens)
+    }
+
+    fn roles_and_texts(messages: &[Message]) -> Vec<(String, String)> {
+        messages.iter().map(|message| (message.role.clone(), message.content.text())).collect()
+    }
+
+    #[test]
+    fn select_history_keeps_the_newest_turns_that_fit() {
+        let chatlog = [
+            log("user", "q1", 10),
+            log("assistant", "a1", 10),
+            log("user", "q2", 10),
+            log("assistant", "a2", 10),
+        ];
+        let texts: Vec<String> = select_history(&chatlog, 25).iter().map(|message| message.content.text()).collect();
+        assert_eq!(texts, ["q2", "a2"]);
+        assert_eq!(select_history(&chatlog, 40).len(), 4);
+        assert!(select_history(&chatlog, 5).is_empty());
+    }
+
+    #[test]
+    fn select_history_has_no_gaps_and_starts_with_a_question() {
+        let chatlog = [
+            log("user", "q1", 1),
+            log("assistant", "a1", 1),
+            log("user", "q2", 100),
+            log("assistant", "a2", 5),
+            log("user", "q3", 5),
+            log("assistant", "a3", 5),
+        ];
+        // q1 and a1 would fit, but not without q2 and a2 in between. a2 fits without its question
+        let texts: Vec<String> = select_history(&chatlog, 16).iter().map(|message| message.content.text()).collect();
+        assert_eq!(texts, ["q3", "a3"]);
+    }
+
+    #[test]
+    fn select_history_sends_summaries_and_tool_calls_as_system_context() {
+        let chatlog = [
+            log("system", "Be brief.", 1000),
+            log("summary", "They talked about Rust.", 5),
+            log("user", "what time is it?", 5),
+            log("tool", "get_current_time({}) -> 12:00", 5),
+            log("assistant", "noon", 5),
+        ];
+        // the system prompt is sent by build_messages and doesn't count against the budget
+        assert_eq!(
+            roles_and_texts(&select_history(&chatlog, 20)),
+            [
+                ("system".to_string(), "They talked about Rust.".to_string()),
+                ("user".to_string(), "what time is it?".to_string()),
+                ("system".to_string(), "get_current_time({}) -> 12:00".to_string()),
+                ("assistant".to_string(), "noon".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn build_messages_leaves_room_for_the_prompt() {
+        let chatlog = [
+            log("user", "q1", 10),
+            log("assistant", "a1", 10),
+            log("user", "q2", 10),
+            log("assistant", "a2", 10),
+        ];
+        let prompt = "and then?";
+        assert!(count_tokens(prompt, "gpt-4o") > 0);
+
+        // the whole history fits the budget on its own, but not next to the prompt
+        assert_eq!(select_history(&chatlog, 40).len(), 4);
+        let messages = build_messages(&chatlog, Some("Be brief."), prompt, "gpt-4o", 40);
+        assert_eq!(
+            roles_and_texts(&messages),
+            [
+                ("system".to_string(), "Be brief.".to_string()),
+                ("user".to_string(), "q2".to_string()),
+                ("assistant".to_string(), "a2".to_string()),
+                ("user".to_string(), prompt.to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn build_request_uses_the_settings() {
+        let settings = RequestSettings {
+            model: "gpt-4o".to_string(),
+            temperature: Some(0.2),
+            choices: Some(1),
+            json: true,
+            stream: true,
+            stream_usage: true,
+            roles: HashMap::from([("system".to_string(), "user".to_string())]),
+            role_prefix: Some("[{role}] ".to_string()),
+            ..Default::default()
+        };
+        let messages = vec![
+            create_message("system".to_string(), "Be brief.".to_string()),
+            create_message("user".to_string(), "hi".to_string()),
+        ];
+        let request = serde_json::to_value(build_request(&settings, messages)).unwrap();
+        assert_eq!(
+            request,
+            serde_json::json!({
+                "model": "gpt-4o",
+                "messages": [
+                    { "role": "user", "content": "[system] Be brief." },
+                    { "role": "user", "content": "hi" },
+                ],
+                "temperature": 0.2f32,
+                "response_format": { "type": "json_object" },
+                "stream": true,
+                "stream_options": { "include_usage": true },
+            })
+        );
+    }
+
+    // a conversation in a directory of its own under the temporary directory
+    fn temp_chatlog(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("ask-test-{}-{}", name, process::id()));
+        fs::remove_dir_all(&dir).ok();
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("ask_log.json")
+    }
+
+    #[test]
+    fn lock_chatlog_keeps_the_turns_of_two_writers() {
+        let chatlog_path = temp_chatlog("lock");
+        let writers: Vec<_> = ["first", "second"]
+            .into_iter()
+            .map(|question| {
+                let chatlog_path = chatlog_path.clone();
+                std::thread::spawn(move || {
+                    let _lock = lock_chatlog(&chatlog_path).unwrap();
+                    let mut chatlog = load_chatlog(&chatlog_path).unwrap();
+                    // long enough for the other writer to read the chatlog too, if it could
+                    std::thread::sleep(Duration::from_millis(200));
+                    chatlog.push(create_log("user".to_string(), question.to_string(), 1));
+                    chatlog.push(create_log("assistant".to_string(), format!("answer to {}", question), 1));
+                    save_chatlog(&chatlog_path, &chatlog).unwrap();
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let chatlog = load_chatlog(&chatlog_path).unwrap();
+        let mut questions: Vec<&str> =
+            chatlog.iter().filter(|log| log.role == "user").map(|log| log.content.as_str()).collect();
+        questions.sort();
+        assert_eq!(questions, ["first", "second"]);
+        assert_eq!(chatlog.len(), 4);
+        fs::remove_dir_all(chatlog_path.parent().unwrap()).ok();
+    }
+
+    #[test]
+    fn delta_content_reads_both_providers() {
+        let openai = serde_json::json!({ "choices": [{ "delta": { "content": "héllo" } }] });
+        let anthropic = serde_json::json!({ "type": "content_block_delta", "delta": { "text": "héllo" } });
+        assert_eq!(delta_content(&openai), Some("héllo"));
+        assert_eq!(delta_content(&anthropic), Some("héllo"));
+        assert_eq!(delta_content(&serde_json::json!({ "usage": {} })), None);
+    }
 }
@@ -0,0 +1,76 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::{fs, path::Path};
+
+use crate::error::AskError;
+use crate::{check_auth, Backend};
+
+#[derive(Serialize, Deserialize)]
+struct ModelCache {
+    fetched_at: String,
+    url: String,
+    models: Vec<String>,
+}
+
+// the models endpoint lives next to the chat endpoint, e.g. /v1/chat/completions -> /v1/models
+fn models_url(api_base: &str) -> String {
+    let base = api_base.trim_end_matches('/');
+    let base = base
+        .strip_suffix("/chat/completions")
+        .or_else(|| base.strip_suffix("/messages"))
+        .unwrap_or(base);
+    format!("{}/models", base)
+}
+
+// a rough filter for models that work with the chat endpoint
+pub fn is_chat_model(id: &str) -> bool {
+    let chat_prefixes = ["gpt-", "chatgpt-", "o1", "o3", "o4", "claude"];
+    let other_kinds = [
+        "instruct", "embedding", "whisper", "tts", "dall-e", "audio", "realtime", "transcribe", "image",
+        "moderation", "search",
+    ];
+    chat_prefixes.iter().any(|prefix| id.starts_with(prefix))
+        && !other_kinds.iter().any(|kind| id.contains(kind))
+}
+
+fn read_cache(cache_path: &Path, url: &str) -> Option<Vec<String>> {
+    let text = fs::read_to_string(cache_path).ok()?;
+    let cache: ModelCache = serde_json::from_str(&text).ok()?;
+    let fetched_at = DateTime::parse_from_rfc3339(&cache.fetched_at).ok()?;
+    let fresh = Utc::now().signed_duration_since(fetched_at) < Duration::days(1);
+    (fresh && cache.url == url).then_some(cache.models)
+}
+
+// the model ids from the provider, cached in ~/.ask/models.json for a day
+pub async fn list_models(backend: &Backend, ask_dir: &Path) -> Result<Vec<String>, AskError> {
+    let url = models_url(&backend.api_base);
+    let cache_path = ask_dir.join("models.json");
+    if let Some(models) = read_cache(&cache_path, &url) {
+        return Ok(models);
+    }
+
+    let response = backend.client.get(&url).headers(backend.headers.clone()).send().await?;
+    let response: serde_json::Value = check_auth(response, backend.key_name).await?.json().await?;
+
+    if let Some(message) = response["error"]["message"].as_str() {
+        return Err(AskError::Api(message.to_string()));
+    }
+    let data = response["data"]
+        .as_array()
+        .ok_or_else(|| AskError::Response("missing data in the models list".to_string()))?;
+    let mut models: Vec<String> = data
+        .iter()
+        .filter_map(|model| model["id"].as_str())
+        .map(str::to_string)
+        .collect();
+    models.sort();
+
+    let cache = ModelCache {
+        fetched_at: Utc::now().to_rfc3339(),
+        url,
+        models,
+    };
+    fs::create_dir_all(ask_dir)?;
+    fs::write(&cache_path, serde_json::to_string(&cache)?)?;
+    Ok(cache.models)
+}
@@ -0,0 +1,49 @@
+use std::{fs, io, path::Path, path::PathBuf};
+
+use crate::error::AskError;
+
+// personas live in ~/.ask/personas/<name>.txt, so the name must stay a plain file name
+fn persona_path(ask_dir: &Path, name: &str) -> Result<PathBuf, AskError> {
+    if name.is_empty() || name.contains('/') || name.contains('\\') || name.contains("..") {
+        return Err(AskError::Config(format!("invalid persona name: {}", name)));
+    }
+    Ok(ask_dir.join("personas").join(format!("{}.txt", name)))
+}
+
+pub fn load(ask_dir: &Path, name: &str) -> Result<String, AskError> {
+    match fs::read_to_string(persona_path(ask_dir, name)?) {
+        Ok(text) => Ok(text.trim().to_string()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Err(AskError::Config(format!(
+            "no persona named {}, save one with ask --save-persona {}",
+            name, name
+        ))),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn save(ask_dir: &Path, name: &str, text: &str) -> Result<PathBuf, AskError> {
+    let path = persona_path(ask_dir, name)?;
+    fs::create_dir_all(ask_dir.join("personas"))?;
+    fs::write(&path, format!("{}\n", text.trim()))?;
+    Ok(path)
+}
+
+pub fn list(ask_dir: &Path) -> Result<Vec<String>, AskError> {
+    let entries = match fs::read_dir(ask_dir.join("personas")) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut names = vec![];
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_some_and(|extension| extension == "txt") {
+            if let Some(name) = path.file_stem() {
+                names.push(name.to_string_lossy().to_string());
+            }
+        }
+    }
+    names.sort();
+    Ok(names)
+}
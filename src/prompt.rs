@@ -0,0 +1,228 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use glob::MatchOptions;
+use ignore::gitignore::GitignoreBuilder;
+use std::fs::File;
+use std::process::{self, Command, Stdio};
+use std::path::{Path, PathBuf};
+use std::{env, fs, io};
+
+// project instructions picked up from the working directory or its parents
+const CONTEXT_FILE: &str = ".ask.md";
+
+// files bigger than this would blow the context window
+const MAX_FILE_REF_BYTES: u64 = 100 * 1024;
+// all the files of --context-glob together, the rest are left out
+const MAX_CONTEXT_GLOB_BYTES: u64 = 400 * 1024;
+
+// read a referenced file into a fenced block labelled with its path
+fn file_block(path: &str) -> io::Result<String> {
+    let size = fs::metadata(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("could not read @{}: {}", path, e)))?
+        .len();
+    if size > MAX_FILE_REF_BYTES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("@{} is {} bytes, the limit is {} bytes", path, size, MAX_FILE_REF_BYTES),
+        ));
+    }
+
+    let contents = fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("could not read @{}: {}", path, e)))?;
+    Ok(fenced_block(Path::new(path), &contents))
+}
+
+fn fenced_block(path: &Path, contents: &str) -> String {
+    let lang = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_string())
+        .unwrap_or_default();
+    format!("{}:\n```{}\n{}\n```", path.display(), lang, contents.trim_end())
+}
+
+// the files matching the patterns as labelled blocks, in path order. files ignored by the .gitignore
+// of the working directory, hidden files, binary files and whatever is over the byte budget are left out
+pub fn glob_files(patterns: &[String]) -> io::Result<Vec<(PathBuf, String)>> {
+    let cwd = env::current_dir()?;
+    let mut gitignore = GitignoreBuilder::new(&cwd);
+    // without a .gitignore nothing is ignored
+    gitignore.add(cwd.join(".gitignore"));
+    let gitignore = gitignore.build().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+    let options = MatchOptions { require_literal_leading_dot: true, ..MatchOptions::new() };
+    let mut paths = vec![];
+    for pattern in patterns {
+        let matches = glob::glob_with(pattern, options)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid glob {}: {}", pattern, e)))?;
+        let before = paths.len();
+        for path in matches.filter_map(Result::ok).filter(|path| path.is_file()) {
+            let absolute = cwd.join(&path);
+            if absolute.starts_with(&cwd) && gitignore.matched_path_or_any_parents(&absolute, false).is_ignore() {
+                continue;
+            }
+            paths.push(path);
+        }
+        if paths.len() == before {
+            eprintln!("Warning: no files match {}", pattern);
+        }
+    }
+    paths.sort();
+    paths.dedup();
+
+    let mut files = vec![];
+    let mut total: u64 = 0;
+    let mut left_out = 0;
+    for path in paths {
+        let size = fs::metadata(&path)?.len();
+        if size > MAX_FILE_REF_BYTES || total + size > MAX_CONTEXT_GLOB_BYTES {
+            left_out += 1;
+            continue;
+        }
+        let Ok(contents) = fs::read_to_string(&path) else {
+            // not text
+            continue;
+        };
+        total += size;
+        let block = fenced_block(&path, &contents);
+        files.push((path, block));
+    }
+    if left_out > 0 {
+        eprintln!(
+            "Warning: left out {} files over the {} byte limit per file or {} bytes in all",
+            left_out, MAX_FILE_REF_BYTES, MAX_CONTEXT_GLOB_BYTES
+        );
+    }
+    Ok(files)
+}
+
+// whether the prompt has @path tokens naming files that expand_file_refs replaces
+pub fn has_file_refs(prompt: &str) -> bool {
+    prompt
+        .split_whitespace()
+        .filter_map(|token| token.strip_prefix('@'))
+        .any(|path| !path.is_empty() && Path::new(path).is_file())
+}
+
+// replace @path tokens with the contents of the file, \@ stays a literal @. a token that isn't a file,
+// e.g. @someone or a path with a trailing period, stays as it is
+pub fn expand_file_refs(prompt: &str) -> io::Result<String> {
+    let mut expanded = String::new();
+    for piece in prompt.split_inclusive(char::is_whitespace) {
+        let token = piece.trim_end_matches(char::is_whitespace);
+        let separator = &piece[token.len()..];
+
+        if let Some(literal) = token.strip_prefix("\\@") {
+            expanded.push('@');
+            expanded.push_str(literal);
+        } else if let Some(path) = token.strip_prefix('@').filter(|path| Path::new(path).is_file()) {
+            expanded.push_str(&file_block(path)?);
+        } else {
+            expanded.push_str(token);
+        }
+        expanded.push_str(separator);
+    }
+    Ok(expanded)
+}
+
+// let the user write the prompt in $EDITOR, starting from the given text
+pub fn edit_prompt(initial: &str) -> io::Result<String> {
+    let editor = env::var("EDITOR")
+        .ok()
+        .filter(|editor| !editor.trim().is_empty())
+        .unwrap_or_else(|| if cfg!(windows) { "notepad" } else { "vi" }.to_string());
+
+    let path = env::temp_dir().join(format!("ask-prompt-{}.md", process::id()));
+    let initial = if initial.is_empty() {
+        String::new()
+    } else {
+        format!("{}\n", initial)
+    };
+    fs::write(&path, initial)?;
+
+    // EDITOR may carry arguments, e.g. "code --wait"
+    let mut parts = editor.split_whitespace();
+    let mut command = Command::new(parts.next().unwrap_or("vi"));
+    command.args(parts).arg(&path);
+
+    // stdin may be a pipe, the editor still needs the terminal
+    if let Ok(tty) = File::open("/dev/tty") {
+        command.stdin(Stdio::from(tty));
+    }
+
+    let status = command.status();
+    let edited = fs::read_to_string(&path);
+    fs::remove_file(&path).ok();
+
+    let status = status.map_err(|e| io::Error::new(e.kind(), format!("could not start {}: {}", editor, e)))?;
+    if !status.success() {
+        return Err(io::Error::other(format!("{} exited with {}", editor, status)));
+    }
+    edited
+}
+
+// look for a .ask.md in the working directory and its parents, stopping at the git root
+pub fn find_context_file() -> Option<PathBuf> {
+    let cwd = env::current_dir().ok()?;
+    for dir in cwd.ancestors() {
+        let path = dir.join(CONTEXT_FILE);
+        if path.is_file() {
+            return Some(path);
+        }
+        if dir.join(".git").exists() {
+            break;
+        }
+    }
+    None
+}
+
+// images bigger than this are rejected by the API anyway
+const MAX_IMAGE_BYTES: u64 = 20 * 1024 * 1024;
+
+// read an image into a base64 data URL
+pub fn image_data_url(path: &Path) -> io::Result<String> {
+    let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidInput, message);
+    let extension = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    let mime = match extension.as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        _ => return Err(invalid(format!("{} is not a png, jpeg, gif or webp image", path.display()))),
+    };
+
+    let size = fs::metadata(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("could not read {}: {}", path.display(), e)))?
+        .len();
+    if size > MAX_IMAGE_BYTES {
+        return Err(invalid(format!("{} is {} bytes, the limit is {} bytes", path.display(), size, MAX_IMAGE_BYTES)));
+    }
+
+    let bytes = fs::read(path)?;
+    Ok(format!("data:{};base64,{}", mime, STANDARD.encode(bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expand_file_refs_leaves_tokens_that_are_not_files() {
+        let dir = env::temp_dir().join(format!("ask-test-refs-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notes.txt");
+        fs::write(&path, "buy milk\n").unwrap();
+
+        let prompt = format!("@{} ask @someone at \\@here, see @{}.", path.display(), path.display());
+        let expanded = expand_file_refs(&prompt).unwrap();
+        assert_eq!(
+            expanded,
+            format!("{}:\n```txt\nbuy milk\n``` ask @someone at @here, see @{}.", path.display(), path.display())
+        );
+        assert!(has_file_refs(&prompt));
+        assert!(!has_file_refs("ask @someone"));
+        fs::remove_dir_all(&dir).ok();
+    }
+}
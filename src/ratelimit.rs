@@ -0,0 +1,87 @@
+use reqwest::header::HeaderMap;
+use std::time::Duration;
+
+// what is left of the rate limit, as the provider reported it in the response headers
+#[derive(Debug, Default)]
+pub struct RateLimit {
+    pub remaining_requests: Option<u64>,
+    pub remaining_tokens: Option<u64>,
+    pub reset_requests: Option<String>,
+    pub reset_tokens: Option<String>,
+}
+
+fn header<'a>(headers: &'a HeaderMap, names: &[&str]) -> Option<&'a str> {
+    names.iter().find_map(|name| headers.get(*name)).and_then(|value| value.to_str().ok())
+}
+
+// OpenAI sends x-ratelimit-*, Anthropic anthropic-ratelimit-*, None when there are neither
+pub fn from_headers(headers: &HeaderMap) -> Option<RateLimit> {
+    let number = |names: &[&str]| header(headers, names).and_then(|value| value.trim().parse().ok());
+    let text = |names: &[&str]| header(headers, names).map(str::to_string);
+    let rate_limit = RateLimit {
+        remaining_requests: number(&["x-ratelimit-remaining-requests", "anthropic-ratelimit-requests-remaining"]),
+        remaining_tokens: number(&["x-ratelimit-remaining-tokens", "anthropic-ratelimit-tokens-remaining"]),
+        reset_requests: text(&["x-ratelimit-reset-requests", "anthropic-ratelimit-requests-reset"]),
+        reset_tokens: text(&["x-ratelimit-reset-tokens", "anthropic-ratelimit-tokens-reset"]),
+    };
+    let empty = rate_limit.remaining_requests.is_none()
+        && rate_limit.remaining_tokens.is_none()
+        && rate_limit.reset_requests.is_none()
+        && rate_limit.reset_tokens.is_none();
+    (!empty).then_some(rate_limit)
+}
+
+// OpenAI resets look like 20ms, 1s or 6m0s
+pub fn parse_reset(value: &str) -> Option<Duration> {
+    let mut total = 0.0;
+    let mut number = String::new();
+    let mut chars = value.trim().chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_ascii_digit() || c == '.' {
+            number.push(c);
+            continue;
+        }
+        let amount: f64 = number.parse().ok()?;
+        number.clear();
+        total += match c {
+            'h' => amount * 3600.0,
+            'm' if chars.peek() == Some(&'s') => {
+                chars.next();
+                amount / 1000.0
+            }
+            'm' => amount * 60.0,
+            's' => amount,
+            _ => return None,
+        };
+    }
+    number.is_empty().then(|| Duration::from_secs_f64(total))
+}
+
+impl RateLimit {
+    // how long until the limit that ran out is reset
+    pub fn wait(&self) -> Option<Duration> {
+        let reset = if self.remaining_requests == Some(0) {
+            &self.reset_requests
+        } else if self.remaining_tokens == Some(0) {
+            &self.reset_tokens
+        } else {
+            return None;
+        };
+        reset.as_deref().and_then(parse_reset)
+    }
+}
+
+// e.g. [rate limit: 99 requests left, 39000 tokens left, reset: 1s]
+pub fn format(rate_limit: &RateLimit) -> String {
+    let mut parts = vec![];
+    if let Some(requests) = rate_limit.remaining_requests {
+        parts.push(format!("{} requests left", requests));
+    }
+    if let Some(tokens) = rate_limit.remaining_tokens {
+        parts.push(format!("{} tokens left", tokens));
+    }
+    if let Some(reset) = rate_limit.reset_requests.as_ref().or(rate_limit.reset_tokens.as_ref()) {
+        parts.push(format!("reset: {}", reset));
+    }
+    format!("[rate limit: {}]", parts.join(", "))
+}
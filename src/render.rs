@@ -0,0 +1,249 @@
+use owo_colors::OwoColorize;
+use similar::{ChangeTag, TextDiff};
+use syntect::easy::HighlightLines;
+use syntect::highlighting::ThemeSet;
+use syntect::parsing::SyntaxSet;
+use syntect::util::{as_24_bit_terminal_escaped, LinesWithEndings};
+use std::env;
+use std::io::{self, IsTerminal};
+use termimad::{gray, MadSkin};
+
+const THEME: &str = "base16-ocean.dark";
+
+// whether to style output: NO_COLOR turns it off, CLICOLOR_FORCE turns it on even when piped,
+// otherwise only a terminal gets colors
+pub fn should_use_color() -> bool {
+    if env::var_os("NO_COLOR").is_some_and(|x| !x.is_empty()) {
+        return false;
+    }
+    if env::var_os("CLICOLOR_FORCE").is_some_and(|x| !x.is_empty() && x != "0") {
+        return true;
+    }
+    io::stdout().is_terminal()
+}
+
+// a piece of the answer, either markdown prose or a fenced code block
+enum Block {
+    Prose(String),
+    Code {
+        lang: String,
+        fence: String,
+        code: String,
+        close: Option<String>,
+    },
+}
+
+// split the text on ``` fences, an unterminated fence runs to the end of the text
+fn split_blocks(text: &str) -> Vec<Block> {
+    let mut blocks = vec![];
+    let mut prose = String::new();
+    let mut code: Option<(String, String, String)> = None;
+
+    for line in LinesWithEndings::from(text) {
+        let trimmed = line.trim();
+        match code.take() {
+            None if trimmed.starts_with("```") => {
+                if !prose.is_empty() {
+                    blocks.push(Block::Prose(std::mem::take(&mut prose)));
+                }
+                let lang = trimmed.trim_start_matches('`').trim().to_string();
+                code = Some((lang, line.to_string(), String::new()));
+            }
+            None => prose.push_str(line),
+            Some((lang, fence, body)) if trimmed == "```" => {
+                blocks.push(Block::Code {
+                    lang,
+                    fence,
+                    code: body,
+                    close: Some(line.to_string()),
+                });
+            }
+            Some((lang, fence, mut body)) => {
+                body.push_str(line);
+                code = Some((lang, fence, body));
+            }
+        }
+    }
+
+    if let Some((lang, fence, body)) = code {
+        blocks.push(Block::Code {
+            lang,
+            fence,
+            code: body,
+            close: None,
+        });
+    }
+    if !prose.is_empty() {
+        blocks.push(Block::Prose(prose));
+    }
+    blocks
+}
+
+// highlight code in the given language, None when the language is unknown or missing
+fn highlight(lang: &str, code: &str) -> Option<String> {
+    if lang.is_empty() {
+        return None;
+    }
+
+    let syntax_set = SyntaxSet::load_defaults_newlines();
+    let syntax = syntax_set.find_syntax_by_token(lang)?;
+    let theme_set = ThemeSet::load_defaults();
+    let mut highlighter = HighlightLines::new(syntax, &theme_set.themes[THEME]);
+
+    let mut highlighted = String::new();
+    for line in LinesWithEndings::from(code) {
+        let ranges = highlighter.highlight_line(line, &syntax_set).ok()?;
+        let escaped = as_24_bit_terminal_escaped(&ranges, false);
+        // reset the colors at the end of each line so nothing bleeds into what follows
+        highlighted.push_str(escaped.trim_end_matches('\n'));
+        highlighted.push_str("\x1b[0m");
+        if line.ends_with('\n') {
+            highlighted.push('\n');
+        }
+    }
+    Some(highlighted)
+}
+
+// wrap long prose lines to the width, code blocks are left alone
+pub fn wrap_prose(text: &str, width: usize) -> String {
+    let mut output = String::new();
+    for block in split_blocks(text) {
+        match block {
+            Block::Prose(prose) => {
+                for line in LinesWithEndings::from(&prose) {
+                    let content = line.trim_end_matches('\n');
+                    if textwrap::core::display_width(content) <= width {
+                        output.push_str(line);
+                        continue;
+                    }
+                    // continuation lines keep the indentation, so nested lists stay nested
+                    let indent = &content[..content.len() - content.trim_start().len()];
+                    let options = textwrap::Options::new(width).subsequent_indent(indent);
+                    output.push_str(&textwrap::fill(content, options));
+                    if line.ends_with('\n') {
+                        output.push('\n');
+                    }
+                }
+            }
+            Block::Code {
+                fence,
+                code,
+                close,
+                ..
+            } => {
+                output.push_str(&fence);
+                output.push_str(&code);
+                output.push_str(&close.unwrap_or_default());
+            }
+        }
+    }
+    output
+}
+
+// syntax highlight fenced code blocks, leaving the prose and the fences untouched
+pub fn highlight_code_blocks(text: &str) -> String {
+    let mut output = String::new();
+    for block in split_blocks(text) {
+        match block {
+            Block::Prose(prose) => output.push_str(&prose),
+            Block::Code {
+                lang,
+                fence,
+                code,
+                close,
+            } => {
+                output.push_str(&fence);
+                output.push_str(&highlight(&lang, &code).unwrap_or(code));
+                output.push_str(&close.unwrap_or_default());
+            }
+        }
+    }
+    output
+}
+
+// render markdown with ANSI styles, code blocks are highlighted and indented
+pub fn render_markdown(text: &str) -> String {
+    let mut skin = MadSkin::default();
+    skin.inline_code.set_bg(gray(3));
+
+    let mut output = String::new();
+    for block in split_blocks(text) {
+        match block {
+            Block::Prose(prose) => output.push_str(&skin.term_text(&prose).to_string()),
+            Block::Code { lang, code, .. } => {
+                let code = highlight(&lang, &code).unwrap_or(code);
+                for line in LinesWithEndings::from(&code) {
+                    output.push_str("  ");
+                    output.push_str(line);
+                }
+                if !output.ends_with('\n') {
+                    output.push('\n');
+                }
+            }
+        }
+    }
+    output
+}
+
+// a line diff from the old answer to the new one, removed lines in red and added ones in green
+pub fn diff_lines(old: &str, new: &str, color: bool) -> String {
+    let mut output = String::new();
+    for change in TextDiff::from_lines(old, new).iter_all_changes() {
+        let line = format!("{}{}", change.tag(), change.value().trim_end_matches('\n'));
+        let line = match (color, change.tag()) {
+            (true, ChangeTag::Delete) => line.red().to_string(),
+            (true, ChangeTag::Insert) => line.green().to_string(),
+            _ => line,
+        };
+        output.push_str(&line);
+        output.push('\n');
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // the variables are shared by the whole process, so the tests take turns
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn with_env(no_color: Option<&str>, clicolor_force: Option<&str>) -> bool {
+        let _guard = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        for (name, value) in [("NO_COLOR", no_color), ("CLICOLOR_FORCE", clicolor_force)] {
+            match value {
+                Some(value) => env::set_var(name, value),
+                None => env::remove_var(name),
+            }
+        }
+        let color = should_use_color();
+        env::remove_var("NO_COLOR");
+        env::remove_var("CLICOLOR_FORCE");
+        color
+    }
+
+    #[test]
+    fn no_color_turns_colors_off() {
+        assert!(!with_env(Some("1"), None));
+        // even when they are forced
+        assert!(!with_env(Some("1"), Some("1")));
+    }
+
+    #[test]
+    fn empty_no_color_is_ignored() {
+        assert!(with_env(Some(""), Some("1")));
+        assert_eq!(with_env(Some(""), None), io::stdout().is_terminal());
+    }
+
+    #[test]
+    fn clicolor_force_turns_colors_on() {
+        assert!(with_env(None, Some("1")));
+    }
+
+    #[test]
+    fn clicolor_force_zero_is_ignored() {
+        assert_eq!(with_env(None, Some("0")), io::stdout().is_terminal());
+        assert_eq!(with_env(None, Some("")), io::stdout().is_terminal());
+    }
+}
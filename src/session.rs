@@ -0,0 +1,51 @@
+use serde::{Deserialize, Serialize};
+use std::{fs, io, path::Path, path::PathBuf};
+
+use crate::error::AskError;
+
+// details about a conversation that don't belong in the chatlog itself
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SessionMeta {
+    pub title: Option<String>,
+    // the model the conversation was last continued with, the default for the next question
+    pub model: Option<String>,
+}
+
+// kept next to the chatlog, e.g. sessions/work.json -> sessions/work.meta.json
+pub fn meta_path(chatlog_path: &Path) -> PathBuf {
+    chatlog_path.with_extension("meta.json")
+}
+
+// a missing or unreadable meta file is the same as no metadata
+pub fn load_meta(chatlog_path: &Path) -> SessionMeta {
+    fs::read_to_string(meta_path(chatlog_path))
+        .ok()
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+pub fn save_meta(chatlog_path: &Path, meta: &SessionMeta) -> Result<(), AskError> {
+    let path = meta_path(chatlog_path);
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(meta)?)?;
+    Ok(())
+}
+
+pub fn save_model(chatlog_path: &Path, model: &str) -> Result<(), AskError> {
+    let mut meta = load_meta(chatlog_path);
+    if meta.model.as_deref() == Some(model) {
+        return Ok(());
+    }
+    meta.model = Some(model.to_string());
+    save_meta(chatlog_path, &meta)
+}
+
+pub fn remove_meta(chatlog_path: &Path) -> Result<(), AskError> {
+    match fs::remove_file(meta_path(chatlog_path)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
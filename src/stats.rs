@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+// (input, output) dollars per 1K tokens, more specific prefixes first
+const PRICES: [(&str, f64, f64); 20] = [
+    ("gpt-4o-mini", 0.00015, 0.0006),
+    ("gpt-4o", 0.0025, 0.01),
+    ("gpt-4.1-nano", 0.0001, 0.0004),
+    ("gpt-4.1-mini", 0.0004, 0.0016),
+    ("gpt-4.1", 0.002, 0.008),
+    ("gpt-4-turbo", 0.01, 0.03),
+    ("gpt-4-32k", 0.06, 0.12),
+    ("gpt-4", 0.03, 0.06),
+    ("gpt-3.5-turbo", 0.0005, 0.0015),
+    ("o1-mini", 0.0011, 0.0044),
+    ("o1", 0.015, 0.06),
+    ("o3-mini", 0.0011, 0.0044),
+    ("o3", 0.002, 0.008),
+    ("o4-mini", 0.0011, 0.0044),
+    ("claude-3-haiku", 0.00025, 0.00125),
+    ("claude-3-5-haiku", 0.0008, 0.004),
+    ("claude-haiku", 0.001, 0.005),
+    ("claude-opus", 0.015, 0.075),
+    ("claude-3-opus", 0.015, 0.075),
+    ("claude", 0.003, 0.015),
+];
+
+fn price_per_1k(model: &str) -> Option<(f64, f64)> {
+    PRICES
+        .iter()
+        .find(|(prefix, _, _)| model.starts_with(prefix))
+        .map(|(_, input, output)| (*input, *output))
+}
+
+// e.g. [prompt: 120, completion: 340, total: 460 tokens, ~$0.0009]
+pub fn format_usage(model: &str, prompt_tokens: i64, completion_tokens: i64) -> String {
+    let total = prompt_tokens + completion_tokens;
+    match price_per_1k(model) {
+        Some((input, output)) => {
+            let cost = prompt_tokens as f64 / 1000.0 * input + completion_tokens as f64 / 1000.0 * output;
+            format!(
+                "[prompt: {}, completion: {}, total: {} tokens, ~${:.4}]",
+                prompt_tokens, completion_tokens, total, cost
+            )
+        }
+        None => format!(
+            "[prompt: {}, completion: {}, total: {} tokens]",
+            prompt_tokens, completion_tokens, total
+        ),
+    }
+}
+
+// e.g. [completion: 340 tokens, 300 reasoning and 40 visible], the reasoning is billed but never shown
+pub fn format_reasoning(completion_tokens: i64, reasoning_tokens: i64) -> String {
+    format!(
+        "[completion: {} tokens, {} reasoning and {} visible]",
+        completion_tokens,
+        reasoning_tokens,
+        completion_tokens - reasoning_tokens
+    )
+}
+
+// e.g. 2.3s, or 350ms under a second
+fn format_duration(duration: Duration) -> String {
+    if duration < Duration::from_secs(1) {
+        format!("{}ms", duration.as_millis())
+    } else {
+        format!("{:.1}s", duration.as_secs_f64())
+    }
+}
+
+// e.g. [latency: 2.3s, first token: 350ms]
+pub fn format_latency(latency: Duration, first_token: Option<Duration>) -> String {
+    match first_token {
+        Some(first_token) => format!(
+            "[latency: {}, first token: {}]",
+            format_duration(latency),
+            format_duration(first_token)
+        ),
+        None => format!("[latency: {}]", format_duration(latency)),
+    }
+}
+
+// one row per token with its log probability, the chance it stands for and the top alternatives
+pub fn format_logprobs(tokens: &[serde_json::Value]) -> String {
+    let mut table = format!("{:<20} {:>9} {:>7}  {}\n", "token", "logprob", "prob", "alternatives");
+    for token in tokens {
+        let logprob = token["logprob"].as_f64().unwrap_or(f64::NEG_INFINITY);
+        let alternatives: Vec<String> = token["top_logprobs"]
+            .as_array()
+            .map(|top| {
+                top.iter()
+                    .map(|alt| {
+                        let logprob = alt["logprob"].as_f64().unwrap_or(f64::NEG_INFINITY);
+                        format!("{:?} {:.3}", alt["token"].as_str().unwrap_or(""), logprob)
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        // debug formatting shows whitespace and newlines in tokens
+        table.push_str(&format!(
+            "{:<20} {:>9.3} {:>6.1}%  {}\n",
+            format!("{:?}", token["token"].as_str().unwrap_or("")),
+            logprob,
+            logprob.exp() * 100.0,
+            alternatives.join(", ")
+        ));
+    }
+    table
+}
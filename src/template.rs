@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use std::{fs, io, path::Path, path::PathBuf};
+
+use crate::error::AskError;
+use crate::prompt;
+
+// templates live in ~/.ask/templates/<name>.txt, so the name must stay a plain file name
+fn template_path(ask_dir: &Path, name: &str) -> Result<PathBuf, AskError> {
+    if name.is_empty() || name.contains('/') || name.contains('\\') || name.contains("..") {
+        return Err(AskError::Config(format!("invalid template name: {}", name)));
+    }
+    Ok(ask_dir.join("templates").join(format!("{}.txt", name)))
+}
+
+// NAME=VALUE pairs from --var, a value of @path is the file like in the prompt
+fn parse_vars(vars: &[String]) -> Result<HashMap<String, String>, AskError> {
+    let mut parsed = HashMap::new();
+    for var in vars {
+        let Some((name, value)) = var.split_once('=') else {
+            return Err(AskError::Config(format!("--var expects NAME=VALUE, got {}", var)));
+        };
+        parsed.insert(name.trim().to_string(), prompt::expand_file_refs(value)?);
+    }
+    Ok(parsed)
+}
+
+// the template with every {{placeholder}} filled in, it's an error to leave one empty.
+// values are inserted as they are, so placeholders in them stay
+pub fn render_template(ask_dir: &Path, name: &str, vars: &[String]) -> Result<String, AskError> {
+    let template = match fs::read_to_string(template_path(ask_dir, name)?) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return Err(AskError::Config(format!(
+                "no template named {}, create {}",
+                name,
+                template_path(ask_dir, name)?.display()
+            )))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let vars = parse_vars(vars)?;
+
+    let mut rendered = String::new();
+    let mut missing: Vec<&str> = vec![];
+    let mut rest = template.as_str();
+    while let Some(start) = rest.find("{{") {
+        let Some(end) = rest[start..].find("}}") else {
+            break;
+        };
+        rendered.push_str(&rest[..start]);
+        let placeholder = rest[start + 2..start + end].trim();
+        match vars.get(placeholder) {
+            Some(value) => rendered.push_str(value),
+            None => {
+                if !missing.contains(&placeholder) {
+                    missing.push(placeholder);
+                }
+            }
+        }
+        rest = &rest[start + end + 2..];
+    }
+    rendered.push_str(rest);
+
+    if !missing.is_empty() {
+        return Err(AskError::Config(format!(
+            "the template {} needs --var for: {}",
+            name,
+            missing.join(", ")
+        )));
+    }
+    Ok(rendered.trim_end().to_string())
+}
@@ -0,0 +1,85 @@
+use chrono::Local;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+// a command from the config file the model may run, e.g.
+// [tools.weather]
+// description = "The weather in a city"
+// command = "curl -s wttr.in/$(jq -r .city)?format=3"
+// parameters = { type = "object", properties = { city = { type = "string" } }, required = ["city"] }
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ToolConfig {
+    pub description: String,
+    pub command: String,
+    pub parameters: Option<serde_json::Value>,
+}
+
+const CURRENT_TIME: &str = "get_current_time";
+
+// the tool definitions for the request, the built-in ones first
+pub fn definitions(tools: &HashMap<String, ToolConfig>) -> Vec<serde_json::Value> {
+    let no_parameters = serde_json::json!({ "type": "object", "properties": {} });
+    let mut definitions = vec![serde_json::json!({
+        "type": "function",
+        "function": {
+            "name": CURRENT_TIME,
+            "description": "The current local date and time with the timezone offset",
+            "parameters": no_parameters,
+        },
+    })];
+
+    let mut names: Vec<&String> = tools.keys().collect();
+    names.sort();
+    for name in names {
+        let tool = &tools[name];
+        definitions.push(serde_json::json!({
+            "type": "function",
+            "function": {
+                "name": name,
+                "description": tool.description,
+                "parameters": tool.parameters.clone().unwrap_or_else(|| no_parameters.clone()),
+            },
+        }));
+    }
+    definitions
+}
+
+// run the tool the model asked for. failures are reported back to the model rather than ending the turn
+pub fn call(tools: &HashMap<String, ToolConfig>, name: &str, arguments: &str) -> String {
+    if name == CURRENT_TIME {
+        return Local::now().to_rfc3339();
+    }
+    match tools.get(name) {
+        Some(tool) => run_command(&tool.command, arguments).unwrap_or_else(|e| format!("error: {}", e)),
+        None => format!("error: there is no tool named {}", name),
+    }
+}
+
+// the command runs in the shell with the JSON arguments on stdin and in ASK_TOOL_ARGS
+fn run_command(command: &str, arguments: &str) -> Result<String, String> {
+    let (shell, flag) = if cfg!(windows) { ("cmd", "/C") } else { ("sh", "-c") };
+    let mut child = Command::new(shell)
+        .arg(flag)
+        .arg(command)
+        .env("ASK_TOOL_ARGS", arguments)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| e.to_string())?;
+    if let Some(mut stdin) = child.stdin.take() {
+        // a command that doesn't read its input closes the pipe early, that's fine
+        stdin.write_all(arguments.as_bytes()).ok();
+    }
+    let output = child.wait_with_output().map_err(|e| e.to_string())?;
+    let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if output.status.success() {
+        Ok(stdout)
+    } else {
+        let stderr = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        Err(format!("{} {}", output.status, stderr))
+    }
+}